@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rlox::compiler::Parser;
+use rlox::diagnostics::SilentReporter;
+use std::rc::Rc;
+
+fuzz_target!(|source: &str| {
+    let mut parser = Parser::init(source);
+    parser.reporter = Rc::new(SilentReporter);
+    parser.compile();
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rlox::compiler::Scanner;
+use rlox::compiler::TokenType;
+
+fuzz_target!(|source: &str| {
+    let mut scanner = Scanner::init(source);
+    while scanner.scan_token().kind != TokenType::Eof {}
+});
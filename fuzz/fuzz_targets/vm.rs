@@ -0,0 +1,24 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rlox::compiler::Parser;
+use rlox::diagnostics::SilentReporter;
+use rlox::vm::VmConfig;
+use rlox::vm::VM;
+use std::rc::Rc;
+
+// Only bytecode produced by the compiler is executed, so the compiler acts
+// as the verifier: the VM must not panic on anything it accepts.
+fuzz_target!(|source: &str| {
+    let mut parser = Parser::init(source);
+    parser.reporter = Rc::new(SilentReporter);
+    if let Some(script) = parser.compile() {
+        let config = VmConfig {
+            max_instructions: Some(100_000),
+            max_frames: 64,
+            ..VmConfig::default()
+        };
+        let mut vm = VM::new(script, config);
+        vm.reporter = Rc::new(SilentReporter);
+        vm.run();
+    }
+});
@@ -0,0 +1,700 @@
+use crate::chunk::Chunk;
+use crate::chunk::Function;
+use crate::chunk::Location;
+use crate::chunk::Native;
+use crate::chunk::Object;
+use crate::chunk::OpCode;
+use crate::chunk::Symbol;
+use crate::chunk::Value;
+use num_bigint::BigInt;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::str::FromStr;
+
+// A textual form of compiled bytecode: `rlox dis --emit-asm` writes it and
+// `rlox asm` assembles it back. Each function is a block,
+//
+//     .function 1 "double" arity 1 upvalues 0 params n
+//     .constant int 2
+//         OP_GET_LOCAL 0
+//         OP_CONSTANT 0
+//         OP_MULTIPLY
+//         OP_RETURN
+//     .end
+//
+// numbered from 0, the script, in the order the blocks appear. A
+// `.doc "text"` line after `.function` holds the function's doc comment. A
+// `.nested N` line declares the function of block N, which must come after
+// the block declaring it, in this one: `OP_CLOSURE 0` wraps the first.
+// Jumps go to labels rather than byte offsets, fields are named and natives
+// are looked up by name, so the file stays valid when it is edited by
+// hand. Comments start with `;`.
+//
+// Constants are `nil`, `true`, `false`, `int 1`, `number 1.5`,
+// `bigint 123`, `string "text"`, `native clock` and
+// `tuple (int 1, int 2)`. A shared constant pool is written out in every
+// block using it, and assembled back into a pool for each.
+
+// What makes an assembly file invalid, and the line it is on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+// Writes `script` and every function declared in it as assembly.
+pub fn write(script: &Function) -> Result<String, String> {
+    let mut out = String::new();
+    write_function(script, 0, &mut out)?;
+    Ok(out)
+}
+
+fn nested(function: &Function) -> impl Iterator<Item = Rc<Function>> + '_ {
+    function.chunk.functions.iter().cloned()
+}
+
+// The number of blocks `function` and the functions declared in it take.
+fn blocks(function: &Function) -> usize {
+    1 + nested(function).map(|f| blocks(&f)).sum::<usize>()
+}
+
+fn write_function(function: &Function, index: usize, out: &mut String) -> Result<(), String> {
+    let mut children = vec![];
+    let mut next = index + 1;
+    for f in nested(function) {
+        let size = blocks(&f);
+        children.push((f, next));
+        next += size;
+    }
+
+    write!(
+        out,
+        ".function {} {} arity {} upvalues {}",
+        index,
+        quote(&function.name),
+        function.arity,
+        function.upvalue_count
+    )
+    .unwrap();
+    if !function.params.is_empty() {
+        write!(out, " params {}", function.params.join(" ")).unwrap();
+    }
+    writeln!(out).unwrap();
+    if let Some(doc) = &function.doc {
+        writeln!(out, ".doc {}", quote(doc)).unwrap();
+    }
+    for constant in function.chunk.constants.iter() {
+        writeln!(out, ".constant {}", constant_text(constant)?).unwrap();
+    }
+    for (_, index) in &children {
+        writeln!(out, ".nested {}", index).unwrap();
+    }
+    write_code(&function.chunk, out)?;
+    writeln!(out, ".end").unwrap();
+
+    for (f, index) in children {
+        writeln!(out).unwrap();
+        write_function(&f, index, out)?;
+    }
+    Ok(())
+}
+
+fn constant_text(constant: &Value) -> Result<String, String> {
+    let text = match constant {
+        Value::Nil => "nil".to_string(),
+        Value::Bool(b) => b.to_string(),
+        // `{:?}` writes the shortest text parsing back to the same number
+        Value::Number(n) => format!("number {:?}", n),
+        Value::Int(i) => format!("int {}", i),
+        Value::Obj(object) => match &**object {
+            Object::Str(s) => format!("string {}", quote(s)),
+            Object::BigInt(i) => format!("bigint {}", i),
+            Object::Native(native) => format!("native {}", native.name),
+            Object::Tuple(values) => {
+                let values: Result<Vec<String>, String> =
+                    values.iter().map(constant_text).collect();
+                format!("tuple ({})", values?.join(", "))
+            }
+            _ => {
+                return Err(format!(
+                    "Cannot write the constant {} as assembly.",
+                    constant.print()
+                ))
+            }
+        },
+        Value::Lifted(_) => {
+            return Err(format!(
+                "Cannot write the constant {} as assembly.",
+                constant.print()
+            ))
+        }
+    };
+    Ok(text)
+}
+
+fn operand(chunk: &Chunk, at: usize) -> u32 {
+    u32::from_be_bytes(chunk.code[at..at + 4].try_into().unwrap())
+}
+
+// Where the jump or loop at `offset` goes, if it is one.
+fn target(chunk: &Chunk, offset: usize) -> Option<usize> {
+    let distance = || operand(chunk, offset + 1) as usize;
+    match chunk.op_at(offset) {
+        OpCode::Jump | OpCode::JumpIfFalse => Some(offset + 5 + distance()),
+        OpCode::Loop => Some(offset + 5 - distance()),
+        _ => None,
+    }
+}
+
+fn write_code(chunk: &Chunk, out: &mut String) -> Result<(), String> {
+    let mut labels = BTreeMap::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        if let Some(target) = target(chunk, offset) {
+            labels.insert(target, 0);
+        }
+        offset += chunk.instruction_size(offset);
+    }
+    for (n, label) in labels.values_mut().enumerate() {
+        *label = n;
+    }
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        if let Some(label) = labels.get(&offset) {
+            writeln!(out, "L{}:", label).unwrap();
+        }
+        let op = chunk.op_at(offset);
+        let size = chunk.instruction_size(offset);
+        write!(out, "    {}", op.name()).unwrap();
+        match op {
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                let label = labels[&target(chunk, offset).unwrap()];
+                write!(out, " L{}", label).unwrap();
+            }
+            OpCode::Closure => {
+                write!(out, " {}", operand(chunk, offset + 1)).unwrap();
+                for capture in (offset + 5..offset + size).step_by(5) {
+                    let kind = if chunk.code[capture] != 0 {
+                        "local"
+                    } else {
+                        "upvalue"
+                    };
+                    write!(out, " {} {}", kind, operand(chunk, capture + 1)).unwrap();
+                }
+            }
+            OpCode::Record => {
+                for at in (offset + 5..offset + size).step_by(4) {
+                    write!(out, " {}", field(operand(chunk, at))?).unwrap();
+                }
+            }
+            OpCode::GetField => {
+                write!(out, " {}", field(operand(chunk, offset + 1))?).unwrap();
+            }
+            _ => {
+                for at in (offset + 1..offset + size).step_by(4) {
+                    write!(out, " {}", operand(chunk, at)).unwrap();
+                }
+            }
+        }
+        writeln!(out).unwrap();
+        offset += size;
+    }
+    if let Some(label) = labels.get(&offset) {
+        writeln!(out, "L{}:", label).unwrap();
+    }
+    Ok(())
+}
+
+fn field(id: u32) -> Result<Rc<str>, String> {
+    Symbol::from_id(id)
+        .map(Symbol::name)
+        .ok_or_else(|| format!("Cannot write the unknown field {} as assembly.", id))
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => write!(quoted, "\\u{{{:x}}}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token<'a> {
+    Word(&'a str),
+    Str(String),
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(text: &str, line: usize) -> Result<Vec<Token<'_>>, AsmError> {
+    let error = |message: &str| AsmError {
+        line,
+        message: message.to_string(),
+    };
+    let mut tokens = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            ';' => break,
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            ',' => tokens.push(Token::Comma),
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(error("Unterminated string.")),
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, 'n')) => s.push('\n'),
+                            Some((_, 'r')) => s.push('\r'),
+                            Some((_, 't')) => s.push('\t'),
+                            Some((_, '"')) => s.push('"'),
+                            Some((_, '\\')) => s.push('\\'),
+                            Some((_, 'u')) => {
+                                let mut hex = String::new();
+                                if chars.next().map(|(_, c)| c) != Some('{') {
+                                    return Err(error("Expect '{' after '\\u'."));
+                                }
+                                loop {
+                                    match chars.next() {
+                                        Some((_, '}')) => break,
+                                        Some((_, c)) => hex.push(c),
+                                        None => return Err(error("Unterminated string.")),
+                                    }
+                                }
+                                let c = u32::from_str_radix(&hex, 16)
+                                    .ok()
+                                    .and_then(std::char::from_u32)
+                                    .ok_or_else(|| error("Invalid '\\u' escape."))?;
+                                s.push(c);
+                            }
+                            _ => return Err(error("Invalid escape in string.")),
+                        },
+                        Some((_, c)) => s.push(c),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(at, c)) = chars.peek() {
+                    if c.is_whitespace() || "(),;\"".contains(c) {
+                        break;
+                    }
+                    end = at + c.len_utf8();
+                    chars.next();
+                }
+                tokens.push(Token::Word(&text[start..end]));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// The tokens of one line, consumed from the left.
+struct Cursor<'a> {
+    tokens: Vec<Token<'a>>,
+    at: usize,
+    line: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn error<T>(&self, message: String) -> Result<T, AsmError> {
+        Err(AsmError {
+            line: self.line,
+            message,
+        })
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn is_done(&self) -> bool {
+        self.at >= self.tokens.len()
+    }
+
+    fn word(&mut self, what: &str) -> Result<&'a str, AsmError> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(word),
+            _ => self.error(format!("Expect {}.", what)),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), AsmError> {
+        match self.next() {
+            Some(Token::Word(word)) if word == keyword => Ok(()),
+            _ => self.error(format!("Expect '{}'.", keyword)),
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, what: &str) -> Result<T, AsmError> {
+        let word = self.word(what)?;
+        word.parse()
+            .or_else(|_| self.error(format!("Expect {}, got '{}'.", what, word)))
+    }
+
+    fn end(&self) -> Result<(), AsmError> {
+        if self.is_done() {
+            Ok(())
+        } else {
+            self.error("Unexpected text at the end of the line.".to_string())
+        }
+    }
+}
+
+struct Block<'a> {
+    line: usize,
+    name: String,
+    arity: u32,
+    upvalues: u32,
+    params: Vec<String>,
+    doc: Option<String>,
+    constants: Vec<Cursor<'a>>,
+    nested: Vec<Cursor<'a>>,
+    code: Vec<Cursor<'a>>,
+}
+
+// Assembles `source` back into the script it was written from. Native
+// constants are looked up in `natives`.
+pub fn parse(source: &str, natives: &[Native]) -> Result<Function, AsmError> {
+    let mut blocks: Vec<Block> = vec![];
+    let mut open = false;
+    let mut last = 0;
+    for (n, text) in source.lines().enumerate() {
+        last = n + 1;
+        let mut cursor = Cursor {
+            tokens: tokenize(text, n + 1)?,
+            at: 0,
+            line: n + 1,
+        };
+        if cursor.is_done() {
+            continue;
+        }
+        match cursor.tokens[0] {
+            Token::Word(".function") => {
+                if open {
+                    return cursor.error("Expect '.end' before the next function.".to_string());
+                }
+                cursor.next();
+                let index: usize = cursor.parse("a function number")?;
+                if index != blocks.len() {
+                    return cursor.error(format!("Expect function {}.", blocks.len()));
+                }
+                let name = match cursor.next() {
+                    Some(Token::Str(name)) => name,
+                    _ => return cursor.error("Expect a function name.".to_string()),
+                };
+                cursor.keyword("arity")?;
+                let arity = cursor.parse("an arity")?;
+                cursor.keyword("upvalues")?;
+                let upvalues = cursor.parse("an upvalue count")?;
+                let mut params = vec![];
+                if !cursor.is_done() {
+                    cursor.keyword("params")?;
+                    while !cursor.is_done() {
+                        params.push(cursor.word("a parameter name")?.to_string());
+                    }
+                }
+                blocks.push(Block {
+                    line: n + 1,
+                    name,
+                    arity,
+                    upvalues,
+                    params,
+                    doc: None,
+                    constants: vec![],
+                    nested: vec![],
+                    code: vec![],
+                });
+                open = true;
+            }
+            _ if !open => return cursor.error("Expect '.function'.".to_string()),
+            Token::Word(".end") => {
+                cursor.next();
+                cursor.end()?;
+                open = false;
+            }
+            Token::Word(".doc") => {
+                cursor.next();
+                match cursor.next() {
+                    Some(Token::Str(doc)) => blocks.last_mut().unwrap().doc = Some(doc),
+                    _ => return cursor.error("Expect a doc comment.".to_string()),
+                }
+                cursor.end()?;
+            }
+            Token::Word(".constant") => {
+                cursor.next();
+                blocks.last_mut().unwrap().constants.push(cursor);
+            }
+            Token::Word(".nested") => {
+                cursor.next();
+                blocks.last_mut().unwrap().nested.push(cursor);
+            }
+            _ => blocks.last_mut().unwrap().code.push(cursor),
+        }
+    }
+    if open {
+        return Err(AsmError {
+            line: last,
+            message: "Expect '.end' after the last function.".to_string(),
+        });
+    }
+    if blocks.is_empty() {
+        return Err(AsmError {
+            line: last.max(1),
+            message: "Expect a function.".to_string(),
+        });
+    }
+
+    let mut assembler = Assembler {
+        natives,
+        used: vec![false; blocks.len()],
+    };
+    let script = assembler.function(&mut blocks, 0)?;
+    if let Some(unused) = assembler.used.iter().skip(1).position(|used| !used) {
+        return Err(AsmError {
+            line: blocks[unused + 1].line,
+            message: format!("Function {} is never used.", unused + 1),
+        });
+    }
+    Ok(script)
+}
+
+struct Assembler<'n> {
+    natives: &'n [Native],
+    // the blocks already declared by a `.nested` line
+    used: Vec<bool>,
+}
+
+// A jump waiting for its label to be placed.
+struct Fixup<'a> {
+    op: OpCode,
+    operand: u32,
+    label: &'a str,
+    line: usize,
+}
+
+impl Assembler<'_> {
+    fn function(&mut self, blocks: &mut [Block], index: usize) -> Result<Function, AsmError> {
+        let block = &mut blocks[index];
+        let mut function = Function::new(block.arity, &block.name);
+        function.upvalue_count = block.upvalues;
+        function.params = block.params.clone();
+        function.doc = block.doc.clone();
+        let mut constants = std::mem::take(&mut block.constants);
+        let nested = std::mem::take(&mut block.nested);
+        let code = std::mem::take(&mut block.code);
+        for cursor in &mut constants {
+            let value = self.value(cursor)?;
+            cursor.end()?;
+            function.chunk.add_constant(value);
+        }
+        for mut cursor in nested {
+            let n: usize = cursor.parse("a function number")?;
+            cursor.end()?;
+            if n <= index || n >= blocks.len() {
+                return cursor.error(format!("Function {} is not declared after this one.", n));
+            }
+            if self.used[n] {
+                return cursor.error(format!("Function {} is already used.", n));
+            }
+            self.used[n] = true;
+            let nested = self.function(blocks, n)?;
+            function.chunk.add_function(Rc::new(nested));
+        }
+        self.code(&mut function.chunk, code)?;
+        Ok(function)
+    }
+
+    fn value(&self, cursor: &mut Cursor) -> Result<Value, AsmError> {
+        let value = match cursor.word("a constant")? {
+            "nil" => Value::Nil,
+            "true" => Value::from_bool(true),
+            "false" => Value::from_bool(false),
+            "int" => Value::from_int(cursor.parse("an integer")?),
+            "number" => Value::from_number(cursor.parse("a number")?),
+            "bigint" => Value::big_int(cursor.parse::<BigInt>("an integer")?),
+            "string" => match cursor.next() {
+                Some(Token::Str(s)) => Value::string(&s),
+                _ => return cursor.error("Expect a string.".to_string()),
+            },
+            "native" => {
+                let name = cursor.word("a native name")?;
+                match self.natives.iter().find(|native| native.name == name) {
+                    Some(native) => Value::native(native.clone()),
+                    None => return cursor.error(format!("Unknown native '{}'.", name)),
+                }
+            }
+            "tuple" => {
+                if cursor.next() != Some(Token::Open) {
+                    return cursor.error("Expect '(' after 'tuple'.".to_string());
+                }
+                let mut values = vec![];
+                if cursor.tokens.get(cursor.at) == Some(&Token::Close) {
+                    cursor.next();
+                } else {
+                    loop {
+                        values.push(self.value(cursor)?);
+                        match cursor.next() {
+                            Some(Token::Comma) => {}
+                            Some(Token::Close) => break,
+                            _ => return cursor.error("Expect ',' or ')'.".to_string()),
+                        }
+                    }
+                }
+                Value::tuple(values)
+            }
+            other => return cursor.error(format!("Unknown constant '{}'.", other)),
+        };
+        Ok(value)
+    }
+
+    fn code<'a>(&mut self, chunk: &mut Chunk, code: Vec<Cursor<'a>>) -> Result<(), AsmError> {
+        let mut labels: HashMap<&'a str, u32> = HashMap::new();
+        let mut fixups = vec![];
+        for mut cursor in code {
+            let at = Location {
+                file: 0,
+                line: cursor.line,
+                column: 1,
+            };
+            let word = cursor.word("an instruction")?;
+            if let Some(label) = word.strip_suffix(':') {
+                if labels.insert(label, chunk.size()).is_some() {
+                    return cursor.error(format!("Label '{}' is already placed.", label));
+                }
+                cursor.end()?;
+                continue;
+            }
+            let op = match OpCode::from_name(word) {
+                Some(op) => op,
+                None => return cursor.error(format!("Unknown instruction '{}'.", word)),
+            };
+            match op {
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                    let label = cursor.word("a label")?;
+                    let operand = chunk.emit(op, &[0], at) + 1;
+                    fixups.push(Fixup {
+                        op,
+                        operand,
+                        label,
+                        line: cursor.line,
+                    });
+                }
+                OpCode::Closure => {
+                    let index: u32 = cursor.parse("a function index")?;
+                    let count = match chunk.functions.get(index as usize) {
+                        Some(function) => function.upvalue_count,
+                        None => {
+                            return cursor
+                                .error(format!("Nested function {} is not declared.", index))
+                        }
+                    };
+                    chunk.emit(op, &[index], at);
+                    for _ in 0..count {
+                        let is_local = match cursor.word("'local' or 'upvalue'")? {
+                            "local" => true,
+                            "upvalue" => false,
+                            _ => return cursor.error("Expect 'local' or 'upvalue'.".to_string()),
+                        };
+                        chunk.write_bool(is_local, at);
+                        chunk.write_u32(cursor.parse("a slot")?, at);
+                    }
+                }
+                OpCode::Record => {
+                    let mut operands = vec![0];
+                    while !cursor.is_done() {
+                        operands.push(Symbol::intern(cursor.word("a field name")?).id());
+                    }
+                    operands[0] = operands.len() as u32 - 1;
+                    chunk.emit(op, &operands, at);
+                }
+                OpCode::GetField => {
+                    let field = Symbol::intern(cursor.word("a field name")?).id();
+                    chunk.emit(op, &[field], at);
+                }
+                OpCode::Constant => {
+                    let index = self.constant(&mut cursor, chunk)?;
+                    chunk.emit(op, &[index], at);
+                }
+                OpCode::CallNamed | OpCode::TupleSpread => {
+                    let count = cursor.parse("a count")?;
+                    let index = self.constant(&mut cursor, chunk)?;
+                    chunk.emit(op, &[count, index], at);
+                }
+                OpCode::CallSpread => {
+                    let count = cursor.parse("a count")?;
+                    let spreads = self.constant(&mut cursor, chunk)?;
+                    let names = self.constant(&mut cursor, chunk)?;
+                    chunk.emit(op, &[count, spreads, names], at);
+                }
+                OpCode::GetLocal
+                | OpCode::SetLocal
+                | OpCode::GetUpvalue
+                | OpCode::SetUpvalue
+                | OpCode::Call
+                | OpCode::Tuple
+                | OpCode::Unpack => {
+                    let operand = cursor.parse("an operand")?;
+                    chunk.emit(op, &[operand], at);
+                }
+                _ => {
+                    chunk.emit(op, &[], at);
+                }
+            }
+            cursor.end()?;
+        }
+
+        for fixup in fixups {
+            let error = |message: String| {
+                Err(AsmError {
+                    line: fixup.line,
+                    message,
+                })
+            };
+            let target = match labels.get(fixup.label) {
+                Some(target) => *target,
+                None => return error(format!("Undefined label '{}'.", fixup.label)),
+            };
+            let after = fixup.operand + 4;
+            let distance = match fixup.op {
+                OpCode::Loop if target <= after => after - target,
+                OpCode::Loop => return error("OP_LOOP can only jump backward.".to_string()),
+                _ if target >= after => target - after,
+                _ => return error(format!("{} can only jump forward.", fixup.op.name())),
+            };
+            let at = fixup.operand as usize;
+            chunk.code[at..at + 4].copy_from_slice(&distance.to_be_bytes());
+        }
+        Ok(())
+    }
+
+    // Reads the index of one of the constants of `chunk`.
+    fn constant(&self, cursor: &mut Cursor, chunk: &Chunk) -> Result<u32, AsmError> {
+        let index: u32 = cursor.parse("a constant index")?;
+        if index as usize >= chunk.constants.len() {
+            return cursor.error(format!("Constant {} is not declared.", index));
+        }
+        Ok(index)
+    }
+}
@@ -1,5 +1,12 @@
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use serde::Serialize;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::fmt;
 use std::rc::Rc;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -7,7 +14,10 @@ pub struct Function {
     pub arity: u32,
     pub chunk: Chunk,
     pub name: String,
+    pub params: Vec<String>,
     pub upvalue_count: u32,
+    // the `///` comment right before the declaration
+    pub doc: Option<String>,
 }
 
 impl Function {
@@ -15,59 +25,346 @@ impl Function {
         Function {
             arity,
             name: name.to_string(),
+            params: vec![],
+            doc: None,
             chunk: Chunk::new(),
             upvalue_count: 0,
         }
     }
+
+    // Writes the disassembly of this function, then of every function
+    // declared in it, outermost first.
+    pub fn write_disassembly(
+        &self,
+        options: DisassemblyOptions,
+        out: &mut dyn fmt::Write,
+    ) -> fmt::Result {
+        self.chunk.write_disassembly(&self.name, options, out)?;
+        for function in &self.chunk.functions {
+            writeln!(out)?;
+            function.write_disassembly(options, out)?;
+        }
+        Ok(())
+    }
+}
+
+// What a disassembly shows besides the instructions themselves.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DisassemblyOptions {
+    // the byte offset of each instruction in its chunk
+    pub offsets: bool,
+    // each chunk's constant pool
+    pub constants: bool,
+    // the source line of each instruction
+    pub lines: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Closure {
     pub function: Rc<Function>,
-    pub upvalues: Vec<UpValue>,
+    pub upvalues: Box<[UpValue]>,
+}
+
+pub type NativeFn = Rc<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+#[derive(Clone)]
+pub struct Native {
+    pub name: String,
+    pub arity: u32,
+    // when set, `arity` is the minimum and any extra arguments are passed on
+    pub variadic: bool,
+    // the permission the VM needs to be given to call it, if any
+    pub capability: Option<Capability>,
+    pub function: NativeFn,
+}
+
+impl Native {
+    pub fn new(name: &str, arity: u32, function: fn(&[Value]) -> Result<Value, String>) -> Self {
+        Native {
+            name: name.to_string(),
+            arity,
+            variadic: false,
+            capability: None,
+            function: Rc::new(function),
+        }
+    }
+
+    pub fn variadic(
+        name: &str,
+        min_arity: u32,
+        function: fn(&[Value]) -> Result<Value, String>,
+    ) -> Self {
+        Native {
+            variadic: true,
+            ..Native::new(name, min_arity, function)
+        }
+    }
+
+    pub fn needing(self, capability: Capability) -> Self {
+        Native {
+            capability: Some(capability),
+            ..self
+        }
+    }
+}
+
+// The permissions natives can require. See `VmConfig::permissions`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    Fs,
+    Net,
+    Time,
+}
+
+impl Capability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Fs => "fs",
+            Capability::Net => "net",
+            Capability::Time => "time",
+        }
+    }
+}
+
+// Which capabilities natives may use. The default grants none.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Permissions {
+    pub fs: bool,
+    pub net: bool,
+    pub time: bool,
+}
+
+impl Permissions {
+    pub fn all() -> Self {
+        Permissions {
+            fs: true,
+            net: true,
+            time: true,
+        }
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Fs => self.fs,
+            Capability::Net => self.net,
+            Capability::Time => self.time,
+        }
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.function, &other.function)
+    }
 }
 
 type Lifted<T> = Rc<RefCell<T>>;
 
+// A field name, interned so that records compare names as numbers. The
+// bytecode refers to field names by symbol. Names are never freed, a
+// program only interns the names its source spells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Symbols {
+    names: Vec<Rc<str>>,
+    ids: HashMap<Rc<str>, u32>,
+}
+
+thread_local! {
+    static SYMBOLS: RefCell<Symbols> = RefCell::new(Symbols::default());
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        SYMBOLS.with(|symbols| {
+            let mut symbols = symbols.borrow_mut();
+            if let Some(id) = symbols.ids.get(name) {
+                return Symbol(*id);
+            }
+            let id = symbols.names.len() as u32;
+            let name: Rc<str> = Rc::from(name);
+            symbols.names.push(name.clone());
+            symbols.ids.insert(name, id);
+            Symbol(id)
+        })
+    }
+
+    // The symbol numbered `id`, if that many names were interned.
+    pub fn from_id(id: u32) -> Option<Symbol> {
+        SYMBOLS.with(|symbols| ((id as usize) < symbols.borrow().names.len()).then_some(Symbol(id)))
+    }
+
+    pub fn id(self) -> u32 {
+        self.0
+    }
+
+    pub fn name(self) -> Rc<str> {
+        SYMBOLS.with(|symbols| symbols.borrow().names[self.0 as usize].clone())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct UpValue {
     pub location: Lifted<Value>,
 }
 
+// A string that natives append to in place, so that building a long string
+// piece by piece takes linear time. Copies of the value share the buffer.
+#[derive(Clone, Debug)]
+pub struct StringBuilder(pub Rc<RefCell<String>>);
+
+impl PartialEq for StringBuilder {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Object {
     Str(String),
-    Closure(Closure),
+    // shared by every value holding the closure, calls included
+    Closure(Rc<Closure>),
+    Native(Native),
+    Tuple(Vec<Value>),
+    // fields are kept sorted by symbol, so equal records compare equal
+    Record(Vec<(Symbol, Value)>),
+    // only for integers that do not fit an i64, see `Value::big_int`
+    BigInt(BigInt),
+    Bytes(Vec<u8>),
+    StringBuilder(StringBuilder),
 }
 
 impl Object {
     pub fn print(&self) -> String {
         match self {
             Object::Str(s) => s.to_string(),
-            Object::Closure(Closure { function, .. }) => {
-                if function.name == "<script>" {
+            Object::BigInt(i) => i.to_string(),
+            Object::Bytes(bytes) => format!("<bytes {}>", bytes.len()),
+            Object::StringBuilder(builder) => {
+                format!("<string builder {}>", builder.0.borrow().chars().count())
+            }
+            Object::Closure(closure) => {
+                if closure.function.name == "<script>" {
                     "<script>".to_string()
                 } else {
-                    format!("<fn {}>", function.name)
+                    format!("<fn {}>", closure.function.name)
+                }
+            }
+            Object::Native(_) => "<native fn>".to_string(),
+            Object::Tuple(values) => {
+                let values: Vec<String> = values.iter().map(Value::print).collect();
+                if values.len() == 1 {
+                    format!("({},)", values[0])
+                } else {
+                    format!("({})", values.join(", "))
                 }
             }
+            Object::Record(fields) => {
+                let mut fields: Vec<(Rc<str>, &Value)> = fields
+                    .iter()
+                    .map(|(symbol, value)| (symbol.name(), value))
+                    .collect();
+                fields.sort_by(|a, b| a.0.cmp(&b.0));
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}", name, value.print()))
+                    .collect();
+                format!("{{{}}}", fields.join(", "))
+            }
+        }
+    }
+
+    pub fn to_structured(&self) -> Structured {
+        match self {
+            Object::Str(s) => Structured::String(s.clone()),
+            Object::BigInt(i) => Structured::BigInt(i.to_string()),
+            Object::Bytes(bytes) => Structured::Bytes(bytes.clone()),
+            Object::StringBuilder(builder) => Structured::String(builder.0.borrow().clone()),
+            Object::Closure(_) | Object::Native(_) => Structured::Function(self.print()),
+            Object::Tuple(values) => {
+                Structured::Tuple(values.iter().map(Value::to_structured).collect())
+            }
+            Object::Record(fields) => Structured::Record(
+                fields
+                    .iter()
+                    .map(|(symbol, value)| (symbol.name().to_string(), value.to_structured()))
+                    .collect(),
+            ),
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+// A copy of a value as plain data, for embedders to inspect or serialize
+// once a script has run. It serializes untagged: nil is null, tuples are
+// sequences, records are maps and functions the text print shows for
+// them. A string builder becomes the string it holds.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Structured {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Number(f64),
+    // an integer that does not fit an i64, in decimal
+    BigInt(String),
+    String(String),
+    Bytes(Vec<u8>),
+    Tuple(Vec<Structured>),
+    Record(BTreeMap<String, Structured>),
+    Function(String),
+}
+
+// Numbers are integers when written without a decimal point and stay
+// integers through +, - and *. Integers that overflow an i64 are promoted
+// to big integers, and shrink back once they fit. Mixing integers and
+// floats gives a float, and `/` always gives a float.
+#[derive(Clone, Debug)]
 pub enum Value {
     Nil,
     Bool(bool),
     Number(f64),
+    Int(i64),
     Obj(Box<Object>),
     Lifted(Lifted<Value>),
 }
 
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            _ if self.is_number() && other.is_number() => {
+                self.compare_numbers(other) == Some(std::cmp::Ordering::Equal)
+            }
+            (Value::Obj(a), Value::Obj(b)) => a == b,
+            (Value::Lifted(a), Value::Lifted(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
 impl Value {
     pub fn from_number(n: f64) -> Self {
         Value::Number(n)
     }
+    pub fn from_int(i: i64) -> Self {
+        Value::Int(i)
+    }
+    pub fn big_int(i: BigInt) -> Self {
+        match i64::try_from(&i) {
+            Ok(i) => Value::Int(i),
+            Err(_) => Value::Obj(Box::new(Object::BigInt(i))),
+        }
+    }
     pub fn from_bool(b: bool) -> Self {
         Value::Bool(b)
     }
@@ -76,9 +373,29 @@ impl Value {
         Value::Obj(Box::new(string))
     }
     pub fn closure(function: Rc<Function>, upvalues: Vec<UpValue>) -> Self {
-        let closure = Object::Closure(Closure { function, upvalues });
+        let closure = Object::Closure(Rc::new(Closure {
+            function,
+            upvalues: upvalues.into_boxed_slice(),
+        }));
         Value::Obj(Box::new(closure))
     }
+    pub fn native(native: Native) -> Self {
+        Value::Obj(Box::new(Object::Native(native)))
+    }
+    pub fn tuple(values: Vec<Value>) -> Self {
+        Value::Obj(Box::new(Object::Tuple(values)))
+    }
+    pub fn record(mut fields: Vec<(Symbol, Value)>) -> Self {
+        fields.sort_by_key(|field| field.0);
+        Value::Obj(Box::new(Object::Record(fields)))
+    }
+    pub fn bytes(bytes: Vec<u8>) -> Self {
+        Value::Obj(Box::new(Object::Bytes(bytes)))
+    }
+    pub fn string_builder(s: String) -> Self {
+        let builder = StringBuilder(Rc::new(RefCell::new(s)));
+        Value::Obj(Box::new(Object::StringBuilder(builder)))
+    }
     pub fn nil() -> Self {
         Value::Nil
     }
@@ -101,6 +418,51 @@ impl Value {
             false
         }
     }
+    pub fn is_native(&self) -> bool {
+        if let Value::Obj(o) = self {
+            matches!(&**o, Object::Native(_))
+        } else if let Value::Lifted(l) = self {
+            l.borrow().is_native()
+        } else {
+            false
+        }
+    }
+    pub fn is_tuple(&self) -> bool {
+        if let Value::Obj(o) = self {
+            matches!(&**o, Object::Tuple(_))
+        } else if let Value::Lifted(l) = self {
+            l.borrow().is_tuple()
+        } else {
+            false
+        }
+    }
+    pub fn is_record(&self) -> bool {
+        if let Value::Obj(o) = self {
+            matches!(&**o, Object::Record(_))
+        } else if let Value::Lifted(l) = self {
+            l.borrow().is_record()
+        } else {
+            false
+        }
+    }
+    pub fn is_bytes(&self) -> bool {
+        if let Value::Obj(o) = self {
+            matches!(&**o, Object::Bytes(_))
+        } else if let Value::Lifted(l) = self {
+            l.borrow().is_bytes()
+        } else {
+            false
+        }
+    }
+    pub fn is_string_builder(&self) -> bool {
+        if let Value::Obj(o) = self {
+            matches!(&**o, Object::StringBuilder(_))
+        } else if let Value::Lifted(l) = self {
+            l.borrow().is_string_builder()
+        } else {
+            false
+        }
+    }
     pub fn is_bool(&self) -> bool {
         if let Value::Lifted(l) = self {
             l.borrow().is_bool()
@@ -109,16 +471,48 @@ impl Value {
         }
     }
     pub fn is_number(&self) -> bool {
+        match self {
+            Value::Number(_) => true,
+            Value::Lifted(l) => l.borrow().is_number(),
+            _ => self.is_integer(),
+        }
+    }
+    // Whether the value is an integer, big or not.
+    pub fn is_integer(&self) -> bool {
+        match self {
+            Value::Int(_) => true,
+            Value::Obj(o) => matches!(&**o, Object::BigInt(_)),
+            Value::Lifted(l) => l.borrow().is_integer(),
+            _ => false,
+        }
+    }
+    pub fn is_int(&self) -> bool {
         if let Value::Lifted(l) = self {
-            l.borrow().is_number()
+            l.borrow().is_int()
         } else {
-            matches!(self, Value::Number(_))
+            matches!(self, Value::Int(_))
+        }
+    }
+
+    pub fn is_falsey(&self) -> bool {
+        match self {
+            Value::Nil => true,
+            Value::Bool(b) => !b,
+            Value::Lifted(l) => l.borrow().is_falsey(),
+            _ => false,
         }
     }
 
     pub fn as_number(&self) -> f64 {
         if let Value::Number(n) = self {
             *n
+        } else if let Value::Int(i) = self {
+            *i as f64
+        } else if let Value::Obj(o) = self {
+            match &**o {
+                Object::BigInt(i) => i.to_f64().unwrap_or(f64::NAN),
+                _ => panic!("not a number"),
+            }
         } else if let Value::Lifted(l) = self {
             l.borrow().as_number()
         } else {
@@ -126,6 +520,39 @@ impl Value {
         }
     }
 
+    pub fn as_int(&self) -> i64 {
+        if let Value::Int(i) = self {
+            *i
+        } else if let Value::Lifted(l) = self {
+            l.borrow().as_int()
+        } else {
+            panic!("not an integer");
+        }
+    }
+
+    pub fn as_big_int(&self) -> BigInt {
+        match self {
+            Value::Int(i) => BigInt::from(*i),
+            Value::Obj(o) => match &**o {
+                Object::BigInt(i) => i.clone(),
+                _ => panic!("not an integer"),
+            },
+            Value::Lifted(l) => l.borrow().as_big_int(),
+            _ => panic!("not an integer"),
+        }
+    }
+
+    // Orders two numbers, exactly when both are integers.
+    pub fn compare_numbers(&self, other: &Value) -> Option<std::cmp::Ordering> {
+        if self.is_int() && other.is_int() {
+            Some(self.as_int().cmp(&other.as_int()))
+        } else if self.is_integer() && other.is_integer() {
+            Some(self.as_big_int().cmp(&other.as_big_int()))
+        } else {
+            self.as_number().partial_cmp(&other.as_number())
+        }
+    }
+
     pub fn as_bool(&self) -> bool {
         if let Value::Bool(b) = self {
             *b
@@ -165,7 +592,7 @@ impl Value {
         }
     }
 
-    pub fn as_closure(&self) -> Closure {
+    pub fn as_closure(&self) -> Rc<Closure> {
         if let Value::Obj(o) = self {
             if let Object::Closure(c) = &**o {
                 c.clone()
@@ -179,19 +606,135 @@ impl Value {
         }
     }
 
+    pub fn as_native(&self) -> Native {
+        if let Value::Obj(o) = self {
+            if let Object::Native(n) = &**o {
+                n.clone()
+            } else {
+                panic!("not a native");
+            }
+        } else if let Value::Lifted(l) = self {
+            l.borrow().as_native()
+        } else {
+            panic!("not an object");
+        }
+    }
+
+    pub fn as_tuple(&self) -> Vec<Value> {
+        if let Value::Obj(o) = self {
+            if let Object::Tuple(values) = &**o {
+                values.clone()
+            } else {
+                panic!("not a tuple");
+            }
+        } else if let Value::Lifted(l) = self {
+            l.borrow().as_tuple()
+        } else {
+            panic!("not an object");
+        }
+    }
+
+    pub fn as_string_builder(&self) -> StringBuilder {
+        if let Value::Obj(o) = self {
+            if let Object::StringBuilder(builder) = &**o {
+                builder.clone()
+            } else {
+                panic!("not a string builder");
+            }
+        } else if let Value::Lifted(l) = self {
+            l.borrow().as_string_builder()
+        } else {
+            panic!("not an object");
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        if let Value::Obj(o) = self {
+            if let Object::Bytes(bytes) = &**o {
+                bytes.clone()
+            } else {
+                panic!("not bytes");
+            }
+        } else if let Value::Lifted(l) = self {
+            l.borrow().as_bytes()
+        } else {
+            panic!("not an object");
+        }
+    }
+
+    // The value of the record's field named `symbol`.
+    pub fn field(&self, symbol: Symbol) -> Option<Value> {
+        if let Value::Obj(o) = self {
+            if let Object::Record(fields) = &**o {
+                let i = fields.binary_search_by_key(&symbol, |field| field.0).ok()?;
+                Some(fields[i].1.clone())
+            } else {
+                panic!("not a record");
+            }
+        } else if let Value::Lifted(l) = self {
+            l.borrow().field(symbol)
+        } else {
+            panic!("not an object");
+        }
+    }
+
     pub fn print(&self) -> String {
         match self {
             Value::Nil => "nil".to_string(),
             Value::Bool(true) => "true".to_string(),
             Value::Bool(false) => "false".to_string(),
-            Value::Number(f) => f.to_string(),
+            Value::Number(f) => format_number(*f),
+            Value::Int(i) => i.to_string(),
             Value::Obj(o) => o.print(),
             Value::Lifted(l) => l.borrow().print(),
         }
     }
+
+    pub fn to_structured(&self) -> Structured {
+        match self {
+            Value::Nil => Structured::Nil,
+            Value::Bool(b) => Structured::Bool(*b),
+            Value::Number(f) => Structured::Number(*f),
+            Value::Int(i) => Structured::Int(*i),
+            Value::Obj(o) => o.to_structured(),
+            Value::Lifted(l) => l.borrow().to_structured(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.print())
+    }
 }
 
-#[derive(Debug)]
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.print())
+    }
+}
+
+// The shortest text that parses back to `n`, the way JavaScript writes
+// numbers: positional from 1e-6 up to 1e21, with an exponent beyond. It
+// does not depend on the platform or locale, and reads back as a Lox
+// number literal, save for "inf", "-inf" and "NaN", which have none.
+// Whole floats print without a fraction, like integers.
+pub fn format_number(n: f64) -> String {
+    let magnitude = n.abs();
+    if !n.is_finite() || magnitude == 0.0 || (1e-6..1e21).contains(&magnitude) {
+        return n.to_string();
+    }
+    // `{:e}` writes the shortest digits that round-trip, as in "1.5e-8"
+    let text = format!("{:e}", n);
+    let (digits, exponent) = text.split_once('e').unwrap();
+    if exponent.starts_with('-') {
+        format!("{}e{}", digits, exponent)
+    } else {
+        format!("{}e+{}", digits, exponent)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OpCode {
     Return,
     Constant,
@@ -203,7 +746,9 @@ pub enum OpCode {
     Not,
     Equal,
     Greater,
+    GreaterEqual,
     Less,
+    LessEqual,
     Print,
     Nil,
     Pop,
@@ -216,12 +761,25 @@ pub enum OpCode {
     Loop,
     Call,
     Closure,
+    Index,
+    Tuple,
+    Unpack,
+    Contains,
+    CallNamed,
+    Record,
+    GetField,
+    CallSpread,
+    TupleSpread,
+    Trace,
     Debug,
 }
 
-impl From<u8> for OpCode {
-    fn from(b: u8) -> Self {
-        match b {
+// Gives back the byte when it is no opcode.
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(b: u8) -> Result<Self, u8> {
+        Ok(match b {
             0 => OpCode::Return,
             1 => OpCode::Constant,
             2 => OpCode::Divide,
@@ -245,9 +803,21 @@ impl From<u8> for OpCode {
             20 => OpCode::Closure,
             21 => OpCode::GetUpvalue,
             22 => OpCode::SetUpvalue,
+            23 => OpCode::GreaterEqual,
+            24 => OpCode::LessEqual,
+            25 => OpCode::Index,
+            26 => OpCode::Tuple,
+            27 => OpCode::Unpack,
+            28 => OpCode::Contains,
+            29 => OpCode::CallNamed,
+            30 => OpCode::Record,
+            31 => OpCode::GetField,
+            32 => OpCode::CallSpread,
+            33 => OpCode::TupleSpread,
+            34 => OpCode::Trace,
             255 => OpCode::Debug,
-            _ => panic!("unexpected op code"),
-        }
+            _ => return Err(b),
+        })
     }
 }
 
@@ -277,63 +847,328 @@ impl From<OpCode> for u8 {
             OpCode::Closure => 20,
             OpCode::GetUpvalue => 21,
             OpCode::SetUpvalue => 22,
+            OpCode::GreaterEqual => 23,
+            OpCode::LessEqual => 24,
+            OpCode::Index => 25,
+            OpCode::Tuple => 26,
+            OpCode::Unpack => 27,
+            OpCode::Contains => 28,
+            OpCode::CallNamed => 29,
+            OpCode::Record => 30,
+            OpCode::GetField => 31,
+            OpCode::CallSpread => 32,
+            OpCode::TupleSpread => 33,
+            OpCode::Trace => 34,
             OpCode::Debug => 255,
         }
     }
 }
 
+impl OpCode {
+    // The mnemonic of the instruction, in disassemblies and assembly.
+    pub fn name(self) -> &'static str {
+        match self {
+            OpCode::Return => "OP_RETURN",
+            OpCode::Constant => "OP_CONSTANT",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Add => "OP_ADD",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Substract => "OP_SUBSTRACT",
+            OpCode::Not => "OP_NOT",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::GreaterEqual => "OP_GREATER_EQUAL",
+            OpCode::Less => "OP_LESS",
+            OpCode::LessEqual => "OP_LESS_EQUAL",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Nil => "OP_NIL",
+            OpCode::Pop => "OP_POP",
+            OpCode::GetLocal => "OP_GET_LOCAL",
+            OpCode::SetLocal => "OP_SET_LOCAL",
+            OpCode::GetUpvalue => "OP_GET_UPVALUE",
+            OpCode::SetUpvalue => "OP_SET_UPVALUE",
+            OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::Jump => "OP_JUMP",
+            OpCode::Loop => "OP_LOOP",
+            OpCode::Call => "OP_CALL",
+            OpCode::Closure => "OP_CLOSURE",
+            OpCode::Index => "OP_INDEX",
+            OpCode::Tuple => "OP_TUPLE",
+            OpCode::Unpack => "OP_UNPACK",
+            OpCode::Contains => "OP_CONTAINS",
+            OpCode::CallNamed => "OP_CALL_NAMED",
+            OpCode::Record => "OP_RECORD",
+            OpCode::GetField => "OP_GET_FIELD",
+            OpCode::CallSpread => "OP_CALL_SPREAD",
+            OpCode::TupleSpread => "OP_TUPLE_SPREAD",
+            OpCode::Trace => "OP_TRACE",
+            OpCode::Debug => "OP_DEBUG",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<OpCode> {
+        (0..=u8::MAX)
+            .filter_map(|b| OpCode::try_from(b).ok())
+            .find(|op| op.name() == name)
+    }
+}
+
+// The bytecode of one function. Besides the compiler, anything can
+// assemble a chunk with the `emit` family of methods, wrap it in a
+// `Function` and run it with `VM::new`:
+//
+//     let mut script = Function::new(0, "<script>");
+//     let chunk = &mut script.chunk;
+//     chunk.constant(Value::from_int(1), at);
+//     chunk.constant(Value::from_int(2), at);
+//     chunk.emit(OpCode::Add, &[], at);
+//     chunk.emit(OpCode::Print, &[], at);
+//     chunk.emit(OpCode::Nil, &[], at);
+//     chunk.emit(OpCode::Return, &[], at);
+//
+// The VM trusts a chunk to be well formed: run hand-assembled bytecode
+// with `VmConfig::checked` to have each instruction validated first.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: Vec<usize>,
-    pub constants: Vec<Value>,
+    // where each byte of `code` comes from
+    pub locations: Vec<Location>,
+    // shared with the other chunks of the script when it is compiled with
+    // `Parser::shared_constants`
+    pub constants: Rc<Vec<Value>>,
+    // the functions declared in this one, for OP_CLOSURE to wrap
+    pub functions: Vec<Rc<Function>>,
+}
+
+// A position in one of the sources of a script. `file` is the source's
+// id in the script's `SourceMap`; lines and columns count from 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub file: u32,
+    pub line: usize,
+    pub column: usize,
+}
+
+// Names the sources a script is compiled from. Ids are given in the order
+// the sources are added, which must be the order they are given to the
+// compiler: the source a `Parser` is created with, then each `add_file`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    names: Vec<String>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    pub fn add(&mut self, name: &str) -> u32 {
+        self.names.push(name.to_string());
+        (self.names.len() - 1) as u32
+    }
+
+    // The name of source `id`; the prelude, and sources never added, have
+    // none.
+    pub fn name(&self, id: u32) -> Option<&str> {
+        self.names.get(id as usize).map(String::as_str)
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk::new()
+    }
 }
 
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
             code: vec![],
-            constants: vec![],
-            lines: vec![],
+            constants: Rc::new(vec![]),
+            functions: vec![],
+            locations: vec![],
         }
     }
 
-    pub fn write_chunk(&mut self, code: OpCode, line: usize) {
-        if self.code.len() >= u32::MAX as usize {
-            panic!("Source code too long!");
-        }
+    pub fn write_chunk(&mut self, code: OpCode, location: Location) {
         self.code.push(code.into());
-        self.lines.push(line);
+        self.locations.push(location);
     }
 
-    pub fn write_bool(&mut self, b: bool, line: usize) {
+    pub fn write_bool(&mut self, b: bool, location: Location) {
         self.code.push(if b { 1 } else { 0 });
-        self.lines.push(line);
+        self.locations.push(location);
     }
 
-    pub fn write_u32(&mut self, index: u32, line: usize) {
+    pub fn write_u32(&mut self, index: u32, location: Location) {
         for b in index.to_be_bytes() {
-            if self.code.len() >= u32::MAX as usize {
-                panic!("Source code too long!");
-            }
             self.code.push(b);
-            self.lines.push(line);
+            self.locations.push(location);
         }
     }
 
     pub fn add_constant(&mut self, constant: Value) -> u32 {
-        if self.constants.len() >= u32::MAX as usize {
-            panic!("cannot have more than {} constants.", u32::MAX);
+        let constants = Rc::make_mut(&mut self.constants);
+        constants.push(constant);
+        (constants.len() - 1) as u32
+    }
+
+    pub fn add_function(&mut self, function: Rc<Function>) -> u32 {
+        self.functions.push(function);
+        (self.functions.len() - 1) as u32
+    }
+
+    // Appends `op` followed by its u32 operands, and returns the offset of
+    // the instruction. OP_CLOSURE's upvalues, a bool and a u32 each, are
+    // written after it with `write_bool` and `write_u32`.
+    pub fn emit(&mut self, op: OpCode, operands: &[u32], location: Location) -> u32 {
+        let offset = self.size();
+        self.write_chunk(op, location);
+        for operand in operands {
+            self.write_u32(*operand, location);
         }
-        self.constants.push(constant);
-        (self.constants.len() - 1) as u32
+        offset
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+    // Appends an OP_CONSTANT pushing `value`, and returns the constant's
+    // index.
+    pub fn constant(&mut self, value: Value, location: Location) -> u32 {
+        let index = self.add_constant(value);
+        self.emit(OpCode::Constant, &[index], location);
+        index
+    }
+
+    // Appends a forward OP_JUMP or OP_JUMP_IF_FALSE, and returns the offset
+    // of its operand, to give to `patch_jump` once the target is known.
+    pub fn emit_jump(&mut self, op: OpCode, location: Location) -> u32 {
+        self.emit(op, &[u32::MAX], location) + 1
+    }
+
+    // Makes the jump whose operand is at `operand` land at the end of the
+    // chunk.
+    pub fn patch_jump(&mut self, operand: u32) {
+        let jump = self.size() - operand - 4;
+        let at = operand as usize;
+        self.code[at..at + 4].copy_from_slice(&jump.to_be_bytes());
+    }
+
+    // Appends an OP_LOOP jumping back to the instruction at `start`.
+    pub fn emit_loop(&mut self, start: u32, location: Location) {
+        let jump = self.size() + 5 - start;
+        self.emit(OpCode::Loop, &[jump], location);
+    }
+
+    pub fn write_disassembly(
+        &self,
+        name: &str,
+        options: DisassemblyOptions,
+        out: &mut dyn fmt::Write,
+    ) -> fmt::Result {
+        writeln!(out, "== {} ==", name)?;
+        if options.constants {
+            for (i, constant) in self.constants.iter().enumerate() {
+                writeln!(out, "constant {:4} {}", i, constant.print())?;
+            }
+        }
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.write_instruction(offset, options, out)?;
+        }
+        Ok(())
+    }
+
+    // The number of values the instruction at `offset` pops and pushes. A
+    // call's push is its result, which a Lox callee only leaves behind once
+    // it returns; Return pops the callee's whole window besides its value.
+    pub fn stack_effect(&self, offset: usize) -> (usize, usize) {
+        let operand = || {
+            let bytes = &self.code[offset + 1..offset + 5];
+            u32::from_be_bytes(bytes.try_into().unwrap()) as usize
+        };
+        match self.op_at(offset) {
+            OpCode::Return | OpCode::Print | OpCode::Pop | OpCode::Trace => (1, 0),
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::GetLocal
+            | OpCode::GetUpvalue
+            | OpCode::Closure => (0, 1),
+            OpCode::Divide
+            | OpCode::Add
+            | OpCode::Multiply
+            | OpCode::Substract
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Index
+            | OpCode::Contains => (2, 1),
+            OpCode::Negate
+            | OpCode::Not
+            | OpCode::GetField
+            | OpCode::SetLocal
+            | OpCode::SetUpvalue
+            | OpCode::JumpIfFalse => (1, 1),
+            OpCode::Jump | OpCode::Loop | OpCode::Debug => (0, 0),
+            OpCode::Call | OpCode::CallNamed | OpCode::CallSpread => (operand() + 1, 1),
+            // spreading pushes more values than it pops, known only at runtime
+            OpCode::Tuple | OpCode::TupleSpread => (operand(), 1),
+            OpCode::Unpack => (1, operand()),
+            OpCode::Record => (operand(), 1),
+        }
+    }
+
+    // The number of bytes of the instruction at `offset`, operands included.
+    pub fn instruction_size(&self, offset: usize) -> usize {
+        let operand = || {
+            let bytes = &self.code[offset + 1..offset + 5];
+            u32::from_be_bytes(bytes.try_into().unwrap()) as usize
+        };
+        // a byte that is no opcode, which the VM reports, stands alone
+        let op = match OpCode::try_from(self.code[offset]) {
+            Ok(op) => op,
+            Err(_) => return 1,
+        };
+        match op {
+            OpCode::Constant
+            | OpCode::Call
+            | OpCode::Tuple
+            | OpCode::Unpack
+            | OpCode::GetField
+            | OpCode::JumpIfFalse
+            | OpCode::Jump
+            | OpCode::Loop
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue => 5,
+            OpCode::CallNamed | OpCode::TupleSpread => 9,
+            OpCode::CallSpread => 13,
+            // the field count, then the symbol of each field
+            OpCode::Record => 5 + 4 * operand(),
+            // each upvalue is an is_local byte and an index
+            OpCode::Closure => 5 + 5 * self.functions[operand()].upvalue_count as usize,
+            _ => 1,
+        }
+    }
+
+    // The opcode of the instruction at `offset`. Chunks are compiled or
+    // assembled from opcodes, so there always is one.
+    pub fn op_at(&self, offset: usize) -> OpCode {
+        OpCode::try_from(self.code[offset])
+            .unwrap_or_else(|b| panic!("unexpected op code {} at offset {}", b, offset))
+    }
+
+    // The offset of the instruction that byte `offset` belongs to.
+    pub fn instruction_at(&self, offset: usize) -> usize {
+        let mut start = 0;
+        loop {
+            let next = start + self.instruction_size(start);
+            if next > offset {
+                return start;
+            }
+            start = next;
         }
     }
 
@@ -341,24 +1176,45 @@ impl Chunk {
         self.code.len() as u32
     }
 
-    fn disassemble_instruction(&self, mut offset: usize) -> usize {
-        print!("{:04} ", offset);
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
-        } else {
-            print!("{:4} ", self.lines[offset]);
+    fn write_prefix(
+        &self,
+        offset: usize,
+        options: DisassemblyOptions,
+        out: &mut dyn fmt::Write,
+    ) -> fmt::Result {
+        if options.offsets {
+            write!(out, "{:04} ", offset)?;
         }
-        match self.code[offset].into() {
-            OpCode::Return => println!("OP_RETURN"),
+        if options.lines {
+            let line = self.locations[offset].line;
+            if offset > 0 && line == self.locations[offset - 1].line {
+                write!(out, "   | ")?;
+            } else {
+                write!(out, "{:4} ", line)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the instruction at `offset` and returns the offset of the next one.
+    pub fn write_instruction(
+        &self,
+        mut offset: usize,
+        options: DisassemblyOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<usize, fmt::Error> {
+        self.write_prefix(offset, options, out)?;
+        match self.op_at(offset) {
             OpCode::Constant => {
                 let bytes = &self.code[offset + 1..offset + 5];
                 let sized_bytes = bytes.try_into().unwrap();
                 let index = u32::from_be_bytes(sized_bytes);
-                println!(
-                    "OP_CONSTANT      {} '{:?}'",
+                writeln!(
+                    out,
+                    "OP_CONSTANT      {} '{}'",
                     index, self.constants[index as usize]
-                );
-                return offset + 5;
+                )?;
+                return Ok(offset + 5);
             }
             OpCode::Closure => {
                 offset += 1;
@@ -366,9 +1222,9 @@ impl Chunk {
                 offset += 4;
                 let sized_bytes = bytes.try_into().unwrap();
                 let index = u32::from_be_bytes(sized_bytes);
-                let c = &self.constants[index as usize];
-                println!("OP_CLOSURE       {} {}", index, c.print());
-                for _ in 0..c.as_function().upvalue_count {
+                let function = &self.functions[index as usize];
+                writeln!(out, "OP_CLOSURE       {} <fn {}>", index, function.name)?;
+                for _ in 0..function.upvalue_count {
                     let is_local = if self.code[offset] != 0 {
                         "local"
                     } else {
@@ -379,85 +1235,148 @@ impl Chunk {
                     offset += 4;
                     let sized_bytes = bytes.try_into().unwrap();
                     let index = u32::from_be_bytes(sized_bytes);
-                    println!(
-                        "{:04}      |                  {} {}",
-                        offset - 5,
-                        is_local,
-                        index
-                    )
+                    self.write_prefix(offset - 5, options, out)?;
+                    writeln!(out, "                 {} {}", is_local, index)?;
                 }
-                return offset;
+                return Ok(offset);
             }
             OpCode::Call => {
                 let bytes = &self.code[offset + 1..offset + 5];
                 let sized_bytes = bytes.try_into().unwrap();
                 let args_c = u32::from_be_bytes(sized_bytes);
-                println!("OP_CALL          {}", args_c);
-                return offset + 5;
-            }
-            OpCode::Divide => println!("OP_DIVIDE"),
-            OpCode::Add => println!("OP_ADD"),
-            OpCode::Negate => println!("OP_NEGATE"),
-            OpCode::Multiply => println!("OP_MULTIPLY"),
-            OpCode::Substract => println!("OP_SUBSTRACT"),
-            OpCode::Not => println!("OP_NOT"),
-            OpCode::Equal => println!("OP_EQUAL"),
-            OpCode::Greater => println!("OP_GREATER"),
-            OpCode::Less => println!("OP_LESS"),
-            OpCode::Print => println!("OP_PRINT"),
-            OpCode::Nil => println!("OP_NIL"),
-            OpCode::Pop => println!("OP_POP"),
-            OpCode::Debug => println!("OP_DEBUG"),
+                writeln!(out, "OP_CALL          {}", args_c)?;
+                return Ok(offset + 5);
+            }
+            OpCode::CallNamed => {
+                let bytes = &self.code[offset + 1..offset + 5];
+                let args_c = u32::from_be_bytes(bytes.try_into().unwrap());
+                let bytes = &self.code[offset + 5..offset + 9];
+                let index = u32::from_be_bytes(bytes.try_into().unwrap());
+                writeln!(
+                    out,
+                    "OP_CALL_NAMED    {} {}",
+                    args_c,
+                    self.constants[index as usize].print()
+                )?;
+                return Ok(offset + 9);
+            }
+            OpCode::Tuple => {
+                let bytes = &self.code[offset + 1..offset + 5];
+                let sized_bytes = bytes.try_into().unwrap();
+                let count = u32::from_be_bytes(sized_bytes);
+                writeln!(out, "OP_TUPLE         {}", count)?;
+                return Ok(offset + 5);
+            }
+            OpCode::Unpack => {
+                let bytes = &self.code[offset + 1..offset + 5];
+                let sized_bytes = bytes.try_into().unwrap();
+                let count = u32::from_be_bytes(sized_bytes);
+                writeln!(out, "OP_UNPACK        {}", count)?;
+                return Ok(offset + 5);
+            }
+            OpCode::CallSpread => {
+                let bytes = &self.code[offset + 1..offset + 5];
+                let args_c = u32::from_be_bytes(bytes.try_into().unwrap());
+                let bytes = &self.code[offset + 5..offset + 9];
+                let spreads = u32::from_be_bytes(bytes.try_into().unwrap());
+                let bytes = &self.code[offset + 9..offset + 13];
+                let names = u32::from_be_bytes(bytes.try_into().unwrap());
+                writeln!(
+                    out,
+                    "OP_CALL_SPREAD   {} {} {}",
+                    args_c,
+                    self.constants[spreads as usize].print(),
+                    self.constants[names as usize].print()
+                )?;
+                return Ok(offset + 13);
+            }
+            OpCode::TupleSpread => {
+                let bytes = &self.code[offset + 1..offset + 5];
+                let count = u32::from_be_bytes(bytes.try_into().unwrap());
+                let bytes = &self.code[offset + 5..offset + 9];
+                let spreads = u32::from_be_bytes(bytes.try_into().unwrap());
+                writeln!(
+                    out,
+                    "OP_TUPLE_SPREAD  {} {}",
+                    count,
+                    self.constants[spreads as usize].print()
+                )?;
+                return Ok(offset + 9);
+            }
+            OpCode::Record => {
+                let size = self.instruction_size(offset);
+                let names: Vec<String> = self.code[offset + 5..offset + size]
+                    .chunks(4)
+                    .map(|bytes| {
+                        let id = u32::from_be_bytes(bytes.try_into().unwrap());
+                        Symbol(id).name().to_string()
+                    })
+                    .collect();
+                writeln!(
+                    out,
+                    "OP_RECORD        {} ({})",
+                    names.len(),
+                    names.join(", ")
+                )?;
+                return Ok(offset + size);
+            }
+            OpCode::GetField => {
+                let bytes = &self.code[offset + 1..offset + 5];
+                let id = u32::from_be_bytes(bytes.try_into().unwrap());
+                writeln!(out, "OP_GET_FIELD     {} '{}'", id, Symbol(id).name())?;
+                return Ok(offset + 5);
+            }
             OpCode::JumpIfFalse => {
                 let bytes = &self.code[offset + 1..offset + 5];
                 let sized_bytes = bytes.try_into().unwrap();
                 let index = u32::from_be_bytes(sized_bytes);
-                println!("OP_JUMP_IF_FALSE {}", index);
-                return offset + 5;
+                writeln!(out, "OP_JUMP_IF_FALSE {}", index)?;
+                return Ok(offset + 5);
             }
             OpCode::Jump => {
                 let bytes = &self.code[offset + 1..offset + 5];
                 let sized_bytes = bytes.try_into().unwrap();
                 let index = u32::from_be_bytes(sized_bytes);
-                println!("OP_JUMP          {}", index);
-                return offset + 5;
+                writeln!(out, "OP_JUMP          {}", index)?;
+                return Ok(offset + 5);
             }
             OpCode::Loop => {
                 let bytes = &self.code[offset + 1..offset + 5];
                 let sized_bytes = bytes.try_into().unwrap();
                 let index = u32::from_be_bytes(sized_bytes);
-                println!("OP_LOOP          {}", index);
-                return offset + 5;
+                writeln!(out, "OP_LOOP          {}", index)?;
+                return Ok(offset + 5);
             }
             OpCode::GetLocal => {
                 let bytes = &self.code[offset + 1..offset + 5];
                 let sized_bytes = bytes.try_into().unwrap();
                 let index = u32::from_be_bytes(sized_bytes);
-                println!("OP_GET_LOCAL     {}", index);
-                return offset + 5;
+                writeln!(out, "OP_GET_LOCAL     {}", index)?;
+                return Ok(offset + 5);
             }
             OpCode::SetLocal => {
                 let bytes = &self.code[offset + 1..offset + 5];
                 let sized_bytes = bytes.try_into().unwrap();
                 let index = u32::from_be_bytes(sized_bytes);
-                println!("OP_SET_LOCAL     {}", index);
-                return offset + 5;
+                writeln!(out, "OP_SET_LOCAL     {}", index)?;
+                return Ok(offset + 5);
             }
             OpCode::GetUpvalue => {
                 let bytes = &self.code[offset + 1..offset + 5];
                 let sized_bytes = bytes.try_into().unwrap();
                 let index = u32::from_be_bytes(sized_bytes);
-                println!("OP_GET_UPVALUE   {}", index);
-                return offset + 5;
+                writeln!(out, "OP_GET_UPVALUE   {}", index)?;
+                return Ok(offset + 5);
             }
             OpCode::SetUpvalue => {
                 let bytes = &self.code[offset + 1..offset + 5];
                 let sized_bytes = bytes.try_into().unwrap();
                 let index = u32::from_be_bytes(sized_bytes);
-                println!("OP_SET_UPVALUE   {}", index);
-                return offset + 5;
+                writeln!(out, "OP_SET_UPVALUE   {}", index)?;
+                return Ok(offset + 5);
             }
+            op => writeln!(out, "{}", op.name())?,
         }
-        offset + 1
+        Ok(offset + 1)
     }
 }
@@ -1,14 +1,98 @@
 use crate::chunk::Chunk;
 use crate::chunk::Function;
+use crate::chunk::Location;
+use crate::chunk::Native;
+use crate::chunk::Object;
 use crate::chunk::OpCode;
+use crate::chunk::SourceMap;
+use crate::chunk::Symbol;
 use crate::chunk::Value;
+use crate::diagnostics::suggest;
+use crate::diagnostics::At;
+use crate::diagnostics::Diagnostic;
+use crate::diagnostics::ErrorCode;
+use crate::diagnostics::HumanReporter;
+use crate::diagnostics::Reporter;
+use crate::diagnostics::Style;
+use crate::natives;
+use log::debug;
+use log::trace;
+use num_bigint::BigInt;
+use std::collections::HashMap;
 use std::rc::Rc;
 use std::str::FromStr;
 
+pub const PRELUDE: &str = include_str!("prelude.lox");
+// the file number of the prelude, which has no name
+const PRELUDE_FILE: u32 = u32::MAX;
+
+// How large the compiler lets the chunk of one function, or of the script,
+// grow. Operands are u32, which bounds the defaults; embedders compiling
+// untrusted code can set lower limits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompileLimits {
+    // bytes of code
+    pub max_code: usize,
+    pub max_constants: usize,
+}
+
+impl Default for CompileLimits {
+    fn default() -> Self {
+        CompileLimits {
+            max_code: u32::MAX as usize,
+            max_constants: u32::MAX as usize,
+        }
+    }
+}
+
+// What makes two constants of the shared pool interchangeable: their
+// kind and their value, floats bit for bit so that 0 and -0 stay apart.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Number(u64),
+    Str(String),
+    BigInt(String),
+    Native(String),
+    Tuple(Vec<ConstantKey>),
+}
+
+impl ConstantKey {
+    fn of(value: &Value) -> Option<ConstantKey> {
+        Some(match value {
+            Value::Nil => ConstantKey::Nil,
+            Value::Bool(b) => ConstantKey::Bool(*b),
+            Value::Int(i) => ConstantKey::Int(*i),
+            Value::Number(n) => ConstantKey::Number(n.to_bits()),
+            Value::Obj(o) => match &**o {
+                Object::Str(s) => ConstantKey::Str(s.clone()),
+                Object::BigInt(i) => ConstantKey::BigInt(i.to_string()),
+                Object::Native(native) => ConstantKey::Native(native.name.clone()),
+                Object::Tuple(values) => {
+                    ConstantKey::Tuple(values.iter().map(ConstantKey::of).collect::<Option<_>>()?)
+                }
+                _ => return None,
+            },
+            Value::Lifted(_) => return None,
+        })
+    }
+}
+
+// Hands `pool` to `function` and to every function declared in it.
+fn share_constants(function: &mut Function, pool: &Rc<Vec<Value>>) {
+    function.chunk.constants = pool.clone();
+    for nested in &mut function.chunk.functions {
+        share_constants(Rc::make_mut(nested), pool);
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 struct Local<'a> {
     token: Token<'a>,
     depth: Option<usize>,
+    from_prelude: bool,
 }
 
 #[derive(PartialEq, Debug)]
@@ -23,6 +107,17 @@ struct Upvalue {
     is_local: bool,
 }
 
+// A statement postponed by 'defer' to the exits of its scope, kept as the
+// scanner right after its first token so it can be compiled at each exit.
+#[derive(Clone, Debug)]
+struct Deferred<'a> {
+    depth: usize,
+    // the locals declared before the 'defer', the only ones it can see
+    locals: usize,
+    token: Token<'a>,
+    scanner: Scanner<'a>,
+}
+
 #[derive(Debug)]
 struct Compiler<'a> {
     enclosing: Option<Box<Compiler<'a>>>,
@@ -31,6 +126,10 @@ struct Compiler<'a> {
     function: Function,
     kind: FunctionType,
     upvalues: Vec<Upvalue>,
+    deferred: Vec<Deferred<'a>>,
+    in_defer: bool,
+    // whether the chunk outgrew the limits, which is reported once
+    too_large: bool,
 }
 
 impl<'a> Compiler<'a> {
@@ -42,6 +141,9 @@ impl<'a> Compiler<'a> {
             function: Function::new(0, "<script>"),
             kind: FunctionType::Script,
             upvalues: vec![],
+            deferred: vec![],
+            in_defer: false,
+            too_large: false,
         }
     }
 
@@ -66,8 +168,29 @@ impl<'a> Compiler<'a> {
         false
     }
 
-    fn add_local(&mut self, token: Token<'a>) {
-        self.locals.push(Local { token, depth: None })
+    // The declaration that a new local named like `token` would shadow: an
+    // initialized local from an enclosing scope or function.
+    fn shadowed(&self, token: &Token<'a>, scope_depth: usize) -> Option<Token<'a>> {
+        let outer = self.locals.iter().rev().find(|local| {
+            local.token.lexeme == token.lexeme
+                && !local.from_prelude
+                && local.depth.is_some_and(|depth| depth < scope_depth)
+        });
+        match outer {
+            Some(local) => Some(local.token),
+            None => self
+                .enclosing
+                .as_ref()
+                .and_then(|enclosing| enclosing.shadowed(token, usize::MAX)),
+        }
+    }
+
+    fn add_local(&mut self, token: Token<'a>, from_prelude: bool) {
+        self.locals.push(Local {
+            token,
+            depth: None,
+            from_prelude,
+        })
     }
 
     fn resolve_local(&self, name: &str) -> Option<u32> {
@@ -82,14 +205,42 @@ impl<'a> Compiler<'a> {
         None
     }
 
+    fn resolve_binding(&self, name: &str) -> Option<usize> {
+        for local in self.locals.iter().rev() {
+            if name == local.token.lexeme && local.depth.is_some() {
+                return if local.from_prelude {
+                    None
+                } else {
+                    Some(local.token.start)
+                };
+            }
+        }
+        self.enclosing
+            .as_ref()
+            .and_then(|enclosing| enclosing.resolve_binding(name))
+    }
+
+    fn visible_names(&self) -> Vec<&'a str> {
+        let mut names: Vec<&'a str> = self
+            .locals
+            .iter()
+            .filter(|local| local.depth.is_some())
+            .map(|local| local.token.lexeme)
+            .collect();
+        if let Some(enclosing) = &self.enclosing {
+            names.extend(enclosing.visible_names());
+        }
+        names
+    }
+
     fn resolve_upvalue(&mut self, name: &str) -> Option<u32> {
         if let Some(enclosing) = &mut self.enclosing {
             if let Some(local) = enclosing.resolve_local(name) {
                 Some(self.add_upvalue(local, true))
-            } else if let Some(upvalue) = enclosing.resolve_upvalue(name) {
-                Some(self.add_upvalue(upvalue, false))
             } else {
-                None
+                enclosing
+                    .resolve_upvalue(name)
+                    .map(|upvalue| self.add_upvalue(upvalue, false))
             }
         } else {
             None
@@ -136,7 +287,36 @@ pub struct Parser<'a> {
     current: Token<'a>,
     chunk: Option<Chunk>,
     had_error: bool,
+    pub had_warning: bool,
     panic_mode: bool,
+    pub reporter: Rc<dyn Reporter>,
+    pub resolutions: Vec<(usize, usize)>,
+    pub natives: Vec<Native>,
+    pub warn_shadowing: bool,
+    // print the value of every expression statement instead of popping it
+    pub print_exprs: bool,
+    // give every function of the script one constant pool, holding each
+    // distinct constant once, instead of a pool of its own
+    pub shared_constants: bool,
+    // the shared pool while compiling, and where each constant is in it
+    pool: Vec<Value>,
+    pooled: HashMap<ConstantKey, u32>,
+    // whether the pool outgrew the limits, which is reported once
+    pool_too_large: bool,
+    pub limits: CompileLimits,
+    // every name given to a named argument, which the minifier must not
+    // rename since they match parameters by name
+    pub(crate) argument_names: Vec<&'a str>,
+    // names the sources in diagnostics
+    pub sources: Rc<SourceMap>,
+    // the files to compile after the first, see `add_file`
+    more_sources: Vec<&'a str>,
+    last_assignment: Option<usize>,
+    last_comparison: Option<usize>,
+    in_prelude: bool,
+    // compiling a deferred statement again, which reported its errors
+    // the first time
+    replaying: bool,
 }
 
 enum Prefix {
@@ -147,6 +327,7 @@ enum Prefix {
     Number,
     Literal,
     String,
+    Record,
 }
 
 enum Infix {
@@ -155,6 +336,8 @@ enum Infix {
     Or,
     And,
     Call,
+    Index,
+    Field,
 }
 
 struct Rule {
@@ -177,13 +360,17 @@ fn get_rule(kind: &TokenType) -> Rule {
     match kind {
         TokenType::LeftParen => Rule::init(Prefix::Grouping, Infix::Call, Precedence::Call),
         TokenType::RightParen => Rule::init(Prefix::None, Infix::None, Precedence::None),
-        TokenType::LeftBrace => Rule::init(Prefix::None, Infix::None, Precedence::None),
+        TokenType::LeftBrace => Rule::init(Prefix::Record, Infix::None, Precedence::None),
         TokenType::RightBrace => Rule::init(Prefix::None, Infix::None, Precedence::None),
+        TokenType::LeftBracket => Rule::init(Prefix::None, Infix::Index, Precedence::Call),
+        TokenType::RightBracket => Rule::init(Prefix::None, Infix::None, Precedence::None),
         TokenType::Comma => Rule::init(Prefix::None, Infix::None, Precedence::None),
-        TokenType::Dot => Rule::init(Prefix::None, Infix::None, Precedence::None),
+        TokenType::Dot => Rule::init(Prefix::None, Infix::Field, Precedence::Call),
+        TokenType::DotDotDot => Rule::init(Prefix::None, Infix::None, Precedence::None),
         TokenType::Minus => Rule::init(Prefix::Unary, Infix::Binary, Precedence::Term),
         TokenType::Plus => Rule::init(Prefix::None, Infix::Binary, Precedence::Term),
         TokenType::Semicolon => Rule::init(Prefix::None, Infix::None, Precedence::None),
+        TokenType::Colon => Rule::init(Prefix::None, Infix::None, Precedence::None),
         TokenType::Slash => Rule::init(Prefix::None, Infix::Binary, Precedence::Factor),
         TokenType::Star => Rule::init(Prefix::None, Infix::Binary, Precedence::Factor),
         TokenType::Bang => Rule::init(Prefix::Unary, Infix::None, Precedence::None),
@@ -204,6 +391,7 @@ fn get_rule(kind: &TokenType) -> Rule {
         TokenType::For => Rule::init(Prefix::None, Infix::None, Precedence::None),
         TokenType::Fun => Rule::init(Prefix::None, Infix::None, Precedence::None),
         TokenType::If => Rule::init(Prefix::None, Infix::None, Precedence::None),
+        TokenType::In => Rule::init(Prefix::None, Infix::Binary, Precedence::Comparison),
         TokenType::Nil => Rule::init(Prefix::Literal, Infix::None, Precedence::None),
         TokenType::Or => Rule::init(Prefix::None, Infix::Or, Precedence::Or),
         TokenType::Print => Rule::init(Prefix::None, Infix::None, Precedence::None),
@@ -214,6 +402,7 @@ fn get_rule(kind: &TokenType) -> Rule {
         TokenType::Var => Rule::init(Prefix::None, Infix::None, Precedence::None),
         TokenType::While => Rule::init(Prefix::None, Infix::None, Precedence::None),
         TokenType::Debug => Rule::init(Prefix::None, Infix::None, Precedence::None),
+        TokenType::Defer => Rule::init(Prefix::None, Infix::None, Precedence::None),
         TokenType::Error => Rule::init(Prefix::None, Infix::None, Precedence::None),
         TokenType::Eof => Rule::init(Prefix::None, Infix::None, Precedence::None),
     }
@@ -228,36 +417,107 @@ impl<'a> Parser<'a> {
                 kind: TokenType::Error,
                 lexeme: "before file",
                 line: 0,
+                column: 0,
+                file: 0,
+                start: 0,
             },
             current: Token {
                 kind: TokenType::Error,
                 lexeme: "before file",
                 line: 0,
+                column: 0,
+                file: 0,
+                start: 0,
             },
             chunk: None,
             had_error: false,
+            had_warning: false,
             panic_mode: false,
+            reporter: Rc::new(HumanReporter {
+                style: Style::plain(),
+            }),
+            resolutions: vec![],
+            natives: vec![],
+            warn_shadowing: false,
+            print_exprs: false,
+            shared_constants: false,
+            pool: vec![],
+            pooled: HashMap::new(),
+            pool_too_large: false,
+            limits: CompileLimits::default(),
+            argument_names: vec![],
+            sources: Rc::new(SourceMap::new()),
+            more_sources: vec![],
+            last_assignment: None,
+            last_comparison: None,
+            in_prelude: false,
+            replaying: false,
         }
     }
 
+    // Compiles the prelude into the top-level scope, then opens a scope
+    // for the script itself so that its declarations shadow the prelude's.
+    // The natives the prelude calls are added to `natives` if missing.
+    pub fn load_prelude(&mut self) {
+        debug!("compiling the prelude");
+        for native in natives::prelude() {
+            if !self.natives.iter().any(|n| n.name == native.name) {
+                self.natives.push(native);
+            }
+        }
+        let script = std::mem::replace(&mut self.scanner, Scanner::for_file(PRELUDE, PRELUDE_FILE));
+        self.in_prelude = true;
+        self.advance();
+        while !self.matches(TokenType::Eof) {
+            self.declaration();
+        }
+        self.in_prelude = false;
+        self.scanner = script;
+        self.begin_scope();
+    }
+
+    // Compiles `source` after the source the parser was created with, and
+    // any added before it, as file number 1, 2 and so on. The files share
+    // the top-level scope: each one sees what the files before it declare.
+    pub fn add_file(&mut self, source: &'a str) {
+        self.more_sources.push(source);
+    }
+
     pub fn compile(&mut self) -> Option<Function> {
+        debug!("compiling the script");
         self.chunk = Some(Chunk::new());
 
         self.advance();
         while !self.matches(TokenType::Eof) {
             self.declaration();
         }
+        for (i, source) in std::mem::take(&mut self.more_sources)
+            .into_iter()
+            .enumerate()
+        {
+            self.scanner = Scanner::for_file(source, i as u32 + 1);
+            self.advance();
+            while !self.matches(TokenType::Eof) {
+                self.declaration();
+            }
+        }
         self.consume(TokenType::Eof, "Expect end of expression.");
         let function = self.end_compiler();
 
         if self.had_error {
+            debug!("compilation failed");
             None
         } else {
-            Some(function.0)
+            let mut script = function.0;
+            if self.shared_constants {
+                share_constants(&mut script, &Rc::new(std::mem::take(&mut self.pool)));
+            }
+            Some(script)
         }
     }
 
     fn end_compiler(&mut self) -> (Function, Vec<Upvalue>) {
+        self.emit_deferred(0);
         self.emit_return();
         let function = self.compiler.function.clone();
         let upvalues = self.compiler.upvalues.clone();
@@ -265,7 +525,13 @@ impl<'a> Parser<'a> {
         if let Some(enclosing) = self.compiler.enclosing.take() {
             self.compiler = *enclosing;
         }
-        function.chunk.disassemble(&function.name);
+        trace!(
+            "compiled {}: {} bytes, {} constants, {} upvalues",
+            function.name,
+            function.chunk.code.len(),
+            function.chunk.constants.len(),
+            upvalues.len()
+        );
         (function, upvalues)
     }
 
@@ -291,15 +557,23 @@ impl<'a> Parser<'a> {
                 break;
             }
 
-            self.error_at_current(self.current.lexeme);
+            let code = match self.current.lexeme {
+                "Unterminated string." => ErrorCode::UnterminatedString,
+                _ => ErrorCode::UnexpectedCharacter,
+            };
+            self.error_at_current(code, self.current.lexeme);
         }
     }
 
     fn declaration(&mut self) {
+        // read before `fun` is consumed, while it is the token scanned last
+        let doc = self.scanner.doc();
         if self.matches(TokenType::Fun) {
-            self.function_declaration();
+            self.function_declaration(doc);
         } else if self.matches(TokenType::Var) {
             self.var_declaration();
+        } else if self.matches(TokenType::Defer) {
+            self.defer_statement();
         } else {
             self.statement();
         }
@@ -309,18 +583,19 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn function_declaration(&mut self) {
+    fn function_declaration(&mut self, doc: Option<String>) {
         self.parse_variable("Expect function name.");
         self.mark_initialized();
-        self.function(FunctionType::Function);
+        self.function(FunctionType::Function, doc);
     }
 
-    fn function(&mut self, kind: FunctionType) {
+    fn function(&mut self, kind: FunctionType, doc: Option<String>) {
         let mut compiler = Compiler::new();
         if kind != FunctionType::Script {
             compiler.function.name = self.previous.lexeme.to_string();
             compiler.kind = FunctionType::Function;
         }
+        compiler.function.doc = doc;
         let enclosing = std::mem::replace(&mut self.compiler, compiler);
         self.compiler.enclose(enclosing);
 
@@ -330,9 +605,14 @@ impl<'a> Parser<'a> {
             loop {
                 self.compiler.function.arity += 1;
                 if self.compiler.function.arity > 255 {
-                    self.error_at_current("Can't have more than 255 parameters");
+                    self.error_at_current(
+                        ErrorCode::TooManyParameters,
+                        "Can't have more than 255 parameters",
+                    );
                 }
                 self.parse_variable("Expect parameter name.");
+                let name = self.previous.lexeme.to_string();
+                self.compiler.function.params.push(name);
                 self.mark_initialized();
                 if !self.matches(TokenType::Comma) {
                     break;
@@ -345,16 +625,19 @@ impl<'a> Parser<'a> {
         self.block();
 
         let (f, upvalues) = self.end_compiler();
-        let line = self.previous.line;
+        let location = self.previous.location();
         let chunk = self.current_chunk();
-        // TODO here we only need Value::Function
-        let i = chunk.add_constant(Value::closure(Rc::new(f), vec![]));
-        chunk.write_chunk(OpCode::Closure, line);
-        chunk.write_u32(i, line);
+        let i = chunk.add_function(Rc::new(f));
+        chunk.write_chunk(OpCode::Closure, location);
+        chunk.write_u32(i, location);
         for up in upvalues {
-            self.compiler.function.chunk.write_bool(up.is_local, line);
-            self.compiler.function.chunk.write_u32(up.local, line);
+            self.compiler
+                .function
+                .chunk
+                .write_bool(up.is_local, location);
+            self.compiler.function.chunk.write_u32(up.local, location);
         }
+        self.check_limits();
     }
 
     fn synchronize(&mut self) {
@@ -371,6 +654,7 @@ impl<'a> Parser<'a> {
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Var
+                | TokenType::Defer
                 | TokenType::Print
                 | TokenType::Return => return,
                 _ => {}
@@ -380,6 +664,10 @@ impl<'a> Parser<'a> {
     }
 
     fn var_declaration(&mut self) {
+        if self.matches(TokenType::LeftParen) {
+            self.destructuring_declaration();
+            return;
+        }
         self.parse_variable("Expect variable name.");
         if self.matches(TokenType::Equal) {
             self.expression();
@@ -395,6 +683,34 @@ impl<'a> Parser<'a> {
         );
     }
 
+    // 'var (x, y) = t;' declares one local per name, each taking the
+    // element of the tuple at the same position.
+    fn destructuring_declaration(&mut self) {
+        let mut count = 0;
+        loop {
+            self.parse_variable("Expect variable name.");
+            count += 1;
+            if !self.matches(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after variable names.");
+        self.consume(TokenType::Equal, "Expect '=' after variable names.");
+        self.expression();
+        self.emit_local(OpCode::Unpack, count);
+
+        let depth = self.compiler.scope_depth;
+        let first = self.compiler.locals.len() - count as usize;
+        for local in &mut self.compiler.locals[first..] {
+            local.depth = Some(depth);
+        }
+
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+    }
+
     fn mark_initialized(&mut self) {
         let last = self.compiler.locals.last_mut().unwrap();
         last.depth = Some(self.compiler.scope_depth);
@@ -409,9 +725,27 @@ impl<'a> Parser<'a> {
     fn declare_variable(&mut self) {
         let t = self.previous;
         if self.compiler.variable_already_declared(&t) {
-            self.error_at_current("Already a variable with this name in this scope.");
+            self.error_at_current(
+                ErrorCode::AlreadyDeclared,
+                "Already a variable with this name in this scope.",
+            );
         }
-        self.compiler.add_local(t);
+        if self.warn_shadowing {
+            if let Some(outer) = self.compiler.shadowed(&t, self.compiler.scope_depth) {
+                self.warning_at(
+                    &t,
+                    ErrorCode::Shadowing,
+                    &format!(
+                        "'{}' shadows the variable declared on line {}.",
+                        t.lexeme, outer.line
+                    ),
+                );
+            }
+        }
+        if !self.in_prelude {
+            self.resolutions.push((t.start, t.start));
+        }
+        self.compiler.add_local(t, self.in_prelude);
     }
 
     fn statement(&mut self) {
@@ -449,6 +783,9 @@ impl<'a> Parser<'a> {
     }
 
     fn end_scope(&mut self) {
+        let depth = self.compiler.scope_depth;
+        self.emit_deferred(depth);
+        self.compiler.deferred.retain(|d| d.depth < depth);
         self.compiler.end_scope();
         let removed_from_stack = self.compiler.locals_removed_from_stack();
         for _ in 0..removed_from_stack {
@@ -457,9 +794,90 @@ impl<'a> Parser<'a> {
     }
 
     fn expression_statement(&mut self) {
+        let start = self.current.location();
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after expression.");
-        self.emit_byte(OpCode::Pop);
+        if self.print_exprs && !self.in_prelude {
+            // traced at the line the expression starts on
+            self.current_chunk().emit(OpCode::Trace, &[], start);
+            self.check_limits();
+        } else {
+            self.emit_byte(OpCode::Pop);
+        }
+    }
+
+    // 'defer statement;' compiles the statement once to report its errors,
+    // then drops the code: the statement is compiled again at every exit of
+    // the enclosing block, before its locals are popped, and before each
+    // 'return' of the function.
+    fn defer_statement(&mut self) {
+        let deferred = Deferred {
+            depth: self.compiler.scope_depth,
+            locals: self.compiler.locals.len(),
+            token: self.current,
+            scanner: self.scanner.clone(),
+        };
+        // the statement is only compiled here to check it; what it adds
+        // to the chunk is dropped, and emitted again where it runs
+        let chunk = self.current_chunk();
+        let (size, constants, functions) = (
+            chunk.code.len(),
+            chunk.constants.len(),
+            chunk.functions.len(),
+        );
+        let pooled = self.pool.len();
+        let in_defer = std::mem::replace(&mut self.compiler.in_defer, true);
+        self.statement();
+        self.compiler.in_defer = in_defer;
+        let chunk = self.current_chunk();
+        chunk.code.truncate(size);
+        chunk.locations.truncate(size);
+        Rc::make_mut(&mut chunk.constants).truncate(constants);
+        chunk.functions.truncate(functions);
+        self.pool.truncate(pooled);
+        self.pooled.retain(|_, i| (*i as usize) < pooled);
+        self.compiler.deferred.push(deferred);
+    }
+
+    // Compiles the statements deferred in scopes at `depth` or deeper, the
+    // latest first.
+    fn emit_deferred(&mut self, depth: usize) {
+        let deferred: Vec<Deferred<'a>> = self
+            .compiler
+            .deferred
+            .iter()
+            .rev()
+            .take_while(|d| d.depth >= depth)
+            .cloned()
+            .collect();
+        for d in deferred {
+            self.replay(d);
+        }
+    }
+
+    fn replay(&mut self, deferred: Deferred<'a>) {
+        let scanner = std::mem::replace(&mut self.scanner, deferred.scanner);
+        let (previous, current) = (self.previous, self.current);
+        let replaying = std::mem::replace(&mut self.replaying, true);
+        self.current = deferred.token;
+        // hide the locals declared after the 'defer'
+        let hidden: Vec<Option<usize>> = self.compiler.locals[deferred.locals..]
+            .iter_mut()
+            .map(|local| local.depth.take())
+            .collect();
+        let in_defer = std::mem::replace(&mut self.compiler.in_defer, true);
+        self.statement();
+        self.compiler.in_defer = in_defer;
+        for (local, depth) in self.compiler.locals[deferred.locals..]
+            .iter_mut()
+            .zip(hidden)
+        {
+            local.depth = depth;
+        }
+        self.replaying = replaying;
+        self.previous = previous;
+        self.current = current;
+        self.scanner = scanner;
     }
 
     fn debug_statement(&mut self) {
@@ -473,9 +891,21 @@ impl<'a> Parser<'a> {
         self.emit_byte(OpCode::Print);
     }
 
+    fn condition(&mut self) {
+        let start = self.current;
+        self.expression();
+        if self.last_assignment == Some(start.start) {
+            self.warning_at(
+                &start,
+                ErrorCode::AssignmentInCondition,
+                "Assignment used as a condition; use '==' to compare, or wrap it in parentheses if the assignment is intended.",
+            );
+        }
+    }
+
     fn if_statement(&mut self) {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
-        self.expression();
+        self.condition();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
         let then_jump = self.emit_jump(OpCode::JumpIfFalse);
@@ -496,7 +926,7 @@ impl<'a> Parser<'a> {
     fn while_statement(&mut self) {
         let loop_start = self.current_chunk().size();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
-        self.expression();
+        self.condition();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
         let end_jump = self.emit_jump(OpCode::JumpIfFalse);
@@ -513,10 +943,13 @@ impl<'a> Parser<'a> {
         self.begin_scope();
 
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        let mut loop_variable = None;
         if self.matches(TokenType::Semicolon) {
             // No initializer.
         } else if self.matches(TokenType::Var) {
             self.var_declaration();
+            let slot = self.compiler.locals.len() - 1;
+            loop_variable = Some((self.compiler.locals[slot].token, slot as u32));
         } else {
             self.expression_statement();
         }
@@ -524,7 +957,7 @@ impl<'a> Parser<'a> {
         let mut loop_start = self.current_chunk().size();
         let mut exit_jump = None;
         if !self.matches(TokenType::Semicolon) {
-            self.expression();
+            self.condition();
             self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
 
             exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
@@ -543,8 +976,26 @@ impl<'a> Parser<'a> {
             self.patch_jump(body_jump);
         }
 
+        // Each iteration runs the body with its own copy of the loop
+        // variable, so closures created in the body capture that
+        // iteration's value. The copy is written back before the increment.
+        let per_iteration = loop_variable.map(|(token, outer)| {
+            self.begin_scope();
+            self.emit_local(OpCode::GetLocal, outer);
+            self.compiler.add_local(token, false);
+            self.mark_initialized();
+            (outer, self.compiler.locals.len() as u32 - 1)
+        });
+
         self.statement();
 
+        if let Some((outer, inner)) = per_iteration {
+            self.emit_local(OpCode::GetLocal, inner);
+            self.emit_local(OpCode::SetLocal, outer);
+            self.emit_byte(OpCode::Pop);
+            self.end_scope();
+        }
+
         self.emit_loop(loop_start);
 
         if let Some(jump) = exit_jump {
@@ -555,43 +1006,66 @@ impl<'a> Parser<'a> {
         self.end_scope();
     }
 
+    fn emit_local(&mut self, code: OpCode, slot: u32) {
+        let location = self.previous.location();
+        self.emit_byte(code);
+        self.current_chunk().write_u32(slot, location);
+    }
+
     fn emit_loop(&mut self, offset: u32) {
-        self.emit_byte(OpCode::Loop);
-        let line = self.current.line;
-        let chunk = self.current_chunk();
-        let jump = (chunk.size() as i64 + 4) - offset as i64;
-        chunk.write_u32(jump as u32, line);
+        let location = self.previous.location();
+        self.current_chunk().emit_loop(offset, location);
+        self.check_limits();
     }
 
     fn emit_jump(&mut self, code: OpCode) -> u32 {
-        self.emit_byte(code);
-        let line = self.current.line;
-        let chunk = self.current_chunk();
-        chunk.write_u32(u32::MAX, line);
-        chunk.size() - 4
+        let location = self.previous.location();
+        let operand = self.current_chunk().emit_jump(code, location);
+        self.check_limits();
+        operand
     }
 
     fn patch_jump(&mut self, offset: u32) {
-        let chunk = self.current_chunk();
-        let jump = chunk.size() - offset - 4;
-
-        for (i, b) in jump.to_be_bytes().iter().enumerate() {
-            chunk.code[offset as usize + i] = *b;
-        }
+        self.current_chunk().patch_jump(offset);
     }
 
     fn return_statement(&mut self) {
         if self.compiler.kind == FunctionType::Script {
-            self.error_at_current("Can't return from top-level code.");
+            // reported at `return`, as clox does; the statement is still
+            // parsed so the errors after it are found too
+            let at = self.previous;
+            self.error_at(
+                &at,
+                ErrorCode::TopLevelReturn,
+                "Can't return from top-level code.",
+            );
+        }
+        if self.compiler.in_defer {
+            self.error_at_current(
+                ErrorCode::DeferredReturn,
+                "Can't return from a deferred statement.",
+            );
         }
 
         if self.matches(TokenType::Semicolon) {
-            self.emit_return();
+            self.emit_byte(OpCode::Nil);
         } else {
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after return value.");
-            self.emit_byte(OpCode::Return);
         }
+        if !self.compiler.deferred.is_empty() && !self.compiler.in_defer {
+            // the deferred statements run with the returned value on the
+            // stack, held by a local nothing can name
+            let token = Token {
+                lexeme: "",
+                ..self.previous
+            };
+            self.compiler.add_local(token, false);
+            self.mark_initialized();
+            self.emit_deferred(0);
+            self.compiler.locals.pop();
+        }
+        self.emit_byte(OpCode::Return);
     }
 
     fn expression(&mut self) {
@@ -603,7 +1077,7 @@ impl<'a> Parser<'a> {
         let can_assign = prec <= Precedence::Assignment;
         match get_rule(&self.previous.kind).prefix {
             Prefix::None => {
-                self.error_at_current("Expect expression.");
+                self.error_at_current(ErrorCode::ExpectedExpression, "Expect expression.");
                 return;
             }
             Prefix::Variable => self.variable(can_assign),
@@ -612,6 +1086,7 @@ impl<'a> Parser<'a> {
             Prefix::Unary => self.unary(),
             Prefix::Number => self.number(),
             Prefix::String => self.string(),
+            Prefix::Record => self.record(),
         }
 
         while prec <= get_rule(&self.current.kind).precedence {
@@ -619,6 +1094,8 @@ impl<'a> Parser<'a> {
             match get_rule(&self.previous.kind).infix {
                 Infix::None => {}
                 Infix::Call => self.call(),
+                Infix::Index => self.index(),
+                Infix::Field => self.field(),
                 Infix::Binary => self.binary(),
                 Infix::And => self.and(),
                 Infix::Or => self.or(),
@@ -626,7 +1103,10 @@ impl<'a> Parser<'a> {
         }
 
         if can_assign && self.matches(TokenType::Equal) {
-            self.error_at_current("Invalid assigment target.");
+            self.error_at_current(
+                ErrorCode::InvalidAssignmentTarget,
+                "Invalid assigment target.",
+            );
         }
     }
 
@@ -636,31 +1116,55 @@ impl<'a> Parser<'a> {
     }
 
     fn variable(&mut self, can_assign: bool) {
+        if let Some(binding) = self.compiler.resolve_binding(self.previous.lexeme) {
+            if !self.in_prelude {
+                self.resolutions.push((self.previous.start, binding));
+            }
+        }
         if let Some(local) = self.compiler.resolve_local(self.previous.lexeme) {
-            let line = self.previous.line;
+            let location = self.previous.location();
+            let start = self.previous.start;
             if can_assign && self.matches(TokenType::Equal) {
                 self.expression();
+                self.last_assignment = Some(start);
                 self.emit_byte(OpCode::SetLocal);
             } else {
                 self.emit_byte(OpCode::GetLocal);
             }
             let chunk = self.current_chunk();
-            chunk.write_u32(local, line);
+            chunk.write_u32(local, location);
         } else if let Some(upvalue) = self.compiler.resolve_upvalue(self.previous.lexeme) {
-            let line = self.previous.line;
+            let location = self.previous.location();
+            let start = self.previous.start;
             if can_assign && self.matches(TokenType::Equal) {
                 self.expression();
+                self.last_assignment = Some(start);
                 self.emit_byte(OpCode::SetUpvalue);
             } else {
                 self.emit_byte(OpCode::GetUpvalue);
             }
             let chunk = self.current_chunk();
-            chunk.write_u32(upvalue, line);
+            chunk.write_u32(upvalue, location);
+        } else if let Some(native) = self.native(self.previous.lexeme) {
+            self.emit_constant(Value::native(native));
         } else {
-            self.error_at_current(&*format!("Unknown variable '{}'.", self.previous.lexeme));
+            let name = self.previous.lexeme;
+            let mut candidates = self.compiler.visible_names();
+            candidates.extend(self.natives.iter().map(|n| n.name.as_str()));
+            let msg = match suggest(name, &candidates) {
+                Some(candidate) => {
+                    format!("Unknown variable '{}'; did you mean '{}'?", name, candidate)
+                }
+                None => format!("Unknown variable '{}'.", name),
+            };
+            self.error_at_current(ErrorCode::UndefinedVariable, &msg);
         }
     }
 
+    fn native(&self, name: &str) -> Option<Native> {
+        self.natives.iter().find(|n| n.name == name).cloned()
+    }
+
     fn literal(&mut self) {
         match self.previous.kind {
             TokenType::Nil => self.emit_constant(Value::nil()),
@@ -671,13 +1175,84 @@ impl<'a> Parser<'a> {
     }
 
     fn number(&mut self) {
-        let v = f64::from_str(self.previous.lexeme).unwrap();
-        self.emit_constant(Value::from_number(v));
+        let lexeme = self.previous.lexeme;
+        // integer literals too large for i64 are big integers, and so is
+        // a literal with the 'n' suffix
+        let digits = lexeme.strip_suffix('n').unwrap_or(lexeme);
+        let v = if let Ok(i) = i64::from_str(digits) {
+            Value::from_int(i)
+        } else if let Ok(i) = BigInt::from_str(digits) {
+            Value::big_int(i)
+        } else {
+            Value::from_number(f64::from_str(digits).unwrap())
+        };
+        self.emit_constant(v);
     }
 
+    // A parenthesized expression, or a tuple if a comma follows the first
+    // element: '(1, 2)', or '(1,)' for a single element. Elements written
+    // '...t' spread the elements of tuple t, and also make a tuple.
     fn grouping(&mut self) {
+        if self.matches(TokenType::RightParen) {
+            self.emit_local(OpCode::Tuple, 0);
+            return;
+        }
+        let mut spreads = vec![];
+        if self.matches(TokenType::DotDotDot) {
+            spreads.push(Value::from_number(0.0));
+        }
         self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        if !spreads.is_empty() || self.current.kind == TokenType::Comma {
+            let mut count = 1;
+            while self.matches(TokenType::Comma) && self.current.kind != TokenType::RightParen {
+                if self.matches(TokenType::DotDotDot) {
+                    spreads.push(Value::from_number(count as f64));
+                }
+                self.expression();
+                count += 1;
+            }
+            self.consume(TokenType::RightParen, "Expect ')' after tuple elements.");
+            if spreads.is_empty() {
+                self.emit_local(OpCode::Tuple, count);
+            } else {
+                let spreads = self.add_constant(Value::tuple(spreads));
+                self.emit_local(OpCode::TupleSpread, count);
+                let location = self.previous.location();
+                self.current_chunk().write_u32(spreads, location);
+            }
+        } else {
+            self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        }
+    }
+
+    // A record literal, '{x: 1, y: 2}'. The values are evaluated in source
+    // order onto the stack, and the instruction lists the field symbols.
+    fn record(&mut self) {
+        let mut names = vec![];
+        while self.current.kind != TokenType::RightBrace {
+            self.consume(TokenType::Identifier, "Expect field name.");
+            let name = self.previous;
+            let symbol = Symbol::intern(name.lexeme);
+            if names.contains(&symbol) {
+                self.error_at(
+                    &name,
+                    ErrorCode::DuplicateField,
+                    &format!("Field '{}' is given more than once.", name.lexeme),
+                );
+            }
+            names.push(symbol);
+            self.consume(TokenType::Colon, "Expect ':' after field name.");
+            self.expression();
+            if !self.matches(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after record fields.");
+        self.emit_local(OpCode::Record, names.len() as u32);
+        let location = self.previous.location();
+        for symbol in names {
+            self.current_chunk().write_u32(symbol.id(), location);
+        }
     }
 
     fn unary(&mut self) {
@@ -713,20 +1288,81 @@ impl<'a> Parser<'a> {
     }
 
     fn call(&mut self) {
-        let args_c = self.argument_list();
-        self.emit_byte(OpCode::Call);
-        let line = self.current.line;
-        let chunk = self.current_chunk();
-        chunk.write_u32(args_c, line);
+        let (args_c, names, spreads) = self.argument_list();
+        let location = self.current.location();
+        if !spreads.is_empty() {
+            let names = names.iter().map(|name| Value::string(name)).collect();
+            let spreads = self.add_constant(Value::tuple(spreads));
+            let names = self.add_constant(Value::tuple(names));
+            self.emit_byte(OpCode::CallSpread);
+            let chunk = self.current_chunk();
+            chunk.write_u32(args_c, location);
+            chunk.write_u32(spreads, location);
+            chunk.write_u32(names, location);
+        } else if names.is_empty() {
+            self.emit_byte(OpCode::Call);
+            self.current_chunk().write_u32(args_c, location);
+        } else {
+            let names = names.iter().map(|name| Value::string(name)).collect();
+            let i = self.add_constant(Value::tuple(names));
+            self.emit_byte(OpCode::CallNamed);
+            let chunk = self.current_chunk();
+            chunk.write_u32(args_c, location);
+            chunk.write_u32(i, location);
+        }
+    }
+
+    fn index(&mut self) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+        self.emit_byte(OpCode::Index);
+    }
+
+    fn field(&mut self) {
+        self.consume(TokenType::Identifier, "Expect field name after '.'.");
+        let symbol = Symbol::intern(self.previous.lexeme);
+        self.emit_local(OpCode::GetField, symbol.id());
     }
 
-    fn argument_list(&mut self) -> u32 {
+    // Returns the argument count, the names of the trailing named
+    // arguments ('f(1, y: 2)'), which the VM matches to parameters, and the
+    // positions of the arguments to spread ('f(...t)').
+    fn argument_list(&mut self) -> (u32, Vec<&'a str>, Vec<Value>) {
         let mut args_c = 0;
+        let mut names: Vec<&'a str> = vec![];
+        let mut spreads = vec![];
         if !self.matches(TokenType::RightParen) {
             loop {
+                if self.current.kind == TokenType::Identifier
+                    && self.scanner.clone().scan_token().kind == TokenType::Colon
+                {
+                    let name = self.current;
+                    self.advance();
+                    self.advance();
+                    if names.contains(&name.lexeme) {
+                        self.error_at(
+                            &name,
+                            ErrorCode::DuplicateArgument,
+                            &format!("Argument '{}' is given more than once.", name.lexeme),
+                        );
+                    }
+                    names.push(name.lexeme);
+                    self.argument_names.push(name.lexeme);
+                } else if !names.is_empty() {
+                    self.error_at_current(
+                        ErrorCode::PositionalAfterNamed,
+                        "Positional arguments must come before named arguments.",
+                    );
+                }
+                if names.is_empty() && self.matches(TokenType::DotDotDot) {
+                    spreads.push(Value::from_number(args_c as f64));
+                }
                 self.expression();
                 if args_c == 255 {
-                    self.error_at_current("Can't have more than 255 arguments.");
+                    self.error_at_current(
+                        ErrorCode::TooManyArguments,
+                        "Can't have more than 255 arguments.",
+                    );
                 }
                 args_c += 1;
                 if !self.matches(TokenType::Comma) {
@@ -736,13 +1372,27 @@ impl<'a> Parser<'a> {
             self.consume(TokenType::RightParen, "Expect ')' after arguments.");
         }
 
-        args_c
+        (args_c, names, spreads)
     }
 
     fn binary(&mut self) {
         let op_type = self.previous.kind;
         let rule = get_rule(&op_type);
+        let comparison = rule.precedence == Precedence::Comparison;
+        // the left operand is a comparison only if it ended right here,
+        // which parentheses around it prevent
+        if comparison && self.last_comparison == Some(self.previous.start) {
+            let at = self.previous;
+            self.warning_at(
+                &at,
+                ErrorCode::ChainedComparison,
+                "Comparison chained to another comparison; 'a < b < c' compares a boolean to a number, write 'a < b and b < c' instead.",
+            );
+        }
         self.parse_precedence(rule.precedence.next());
+        if comparison {
+            self.last_comparison = Some(self.current.start);
+        }
 
         match op_type {
             TokenType::Plus => self.emit_byte(OpCode::Add),
@@ -755,32 +1405,44 @@ impl<'a> Parser<'a> {
             }
             TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
             TokenType::Less => self.emit_byte(OpCode::Less),
-            TokenType::LessEqual => {
-                self.emit_byte(OpCode::Greater);
-                self.emit_byte(OpCode::Not);
-            }
+            TokenType::LessEqual => self.emit_byte(OpCode::LessEqual),
+            TokenType::In => self.emit_byte(OpCode::Contains),
             TokenType::Greater => self.emit_byte(OpCode::Greater),
-            TokenType::GreaterEqual => {
-                self.emit_byte(OpCode::Less);
-                self.emit_byte(OpCode::Not);
-            }
+            TokenType::GreaterEqual => self.emit_byte(OpCode::GreaterEqual),
             other => panic!("unknown binary operator: {:?}", other),
         }
     }
 
     fn emit_constant(&mut self, v: Value) {
-        let line = self.previous.line;
-        let chunk = self.current_chunk();
-        let i = chunk.add_constant(v);
-        chunk.write_chunk(OpCode::Constant, line);
-        chunk.write_u32(i, line);
+        let location = self.previous.location();
+        let i = self.add_constant(v);
+        self.current_chunk().emit(OpCode::Constant, &[i], location);
+        self.check_limits();
+    }
+
+    // Adds `v` to the constants of the current function, or to the shared
+    // pool, where a constant equal to it is reused.
+    fn add_constant(&mut self, v: Value) -> u32 {
+        if !self.shared_constants {
+            return self.current_chunk().add_constant(v);
+        }
+        let key = ConstantKey::of(&v);
+        if let Some(i) = key.as_ref().and_then(|key| self.pooled.get(key)) {
+            return *i;
+        }
+        self.pool.push(v);
+        let i = (self.pool.len() - 1) as u32;
+        if let Some(key) = key {
+            self.pooled.insert(key, i);
+        }
+        i
     }
 
     fn consume(&mut self, kind: TokenType, msg: &str) {
         if self.current.kind == kind {
             self.advance();
         } else {
-            self.error_at_current(msg);
+            self.error_at_current(ErrorCode::ExpectedToken, msg);
         }
     }
 
@@ -789,54 +1451,128 @@ impl<'a> Parser<'a> {
     }
 
     fn emit_byte(&mut self, b: OpCode) {
-        let line = self.previous.line;
+        let location = self.previous.location();
         let chunk = self.current_chunk();
-        chunk.write_chunk(b, line);
+        chunk.write_chunk(b, location);
+        self.check_limits();
+    }
+
+    // Every instruction goes through `emit_byte`, `emit_constant` or
+    // `function`, which check that the chunk still fits the limits.
+    fn check_limits(&mut self) {
+        if self.compiler.too_large {
+            return;
+        }
+        let chunk = &self.compiler.function.chunk;
+        let msg = if chunk.code.len() > self.limits.max_code {
+            "Too much code in one chunk."
+        } else if self.shared_constants && self.pool.len() > self.limits.max_constants {
+            if self.pool_too_large {
+                return;
+            }
+            self.pool_too_large = true;
+            "Too many constants in one script."
+        } else if chunk.constants.len() > self.limits.max_constants {
+            "Too many constants in one chunk."
+        } else {
+            return;
+        };
+        self.compiler.too_large = true;
+        let at = self.previous;
+        self.error_at(&at, ErrorCode::ChunkTooLarge, msg);
     }
 
-    fn error_at_current(&mut self, lexeme: &str) {
+    fn error_at_current(&mut self, code: ErrorCode, lexeme: &str) {
         let at = self.current;
-        self.error_at(&at, lexeme);
+        self.error_at(&at, code, lexeme);
     }
 
-    fn error_at(&mut self, at: &Token<'_>, msg: &str) {
-        if self.panic_mode {
+    fn error_at(&mut self, at: &Token<'_>, code: ErrorCode, msg: &str) {
+        if self.panic_mode || self.replaying {
             return;
         }
         self.panic_mode = true;
-        eprint!("[line {}] Error", at.line);
-        if at.kind == TokenType::Eof {
-            eprint!(" at end");
-        } else if at.kind == TokenType::Error {
-        } else {
-            eprint!(" at {}", at.lexeme);
-        }
-
-        eprintln!(": {}", msg);
+        self.report(at, code, msg);
         self.had_error = true;
     }
+
+    fn warning_at(&mut self, at: &Token<'_>, code: ErrorCode, msg: &str) {
+        if self.replaying {
+            return;
+        }
+        self.report(at, code, msg);
+        self.had_warning = true;
+    }
+
+    fn report(&self, at: &Token<'_>, code: ErrorCode, msg: &str) {
+        self.reporter.report(&Diagnostic {
+            code,
+            file: self.sources.name(at.file).map(str::to_string),
+            line: at.line,
+            column: at.column,
+            message: msg.to_string(),
+            at: match at.kind {
+                TokenType::Eof => Some(At::End),
+                TokenType::Error => None,
+                _ => Some(At::Lexeme(at.lexeme.to_string())),
+            },
+            frames: vec![],
+        });
+    }
 }
 
-struct Scanner<'a> {
+pub const KEYWORDS: [&str; 19] = [
+    "and", "class", "debug", "defer", "else", "false", "for", "fun", "if", "in", "nil", "or",
+    "print", "return", "super", "this", "true", "var", "while",
+];
+
+#[derive(Clone, Debug)]
+pub struct Scanner<'a> {
     source: &'a str,
     start: usize,
     current: usize,
     line: usize,
+    // the column the token starts at, and the column of `current`
+    column: usize,
+    current_column: usize,
+    file: u32,
+    // the `///` lines right before the token scanned last
+    doc: Vec<&'a str>,
 }
 
 impl<'a> Scanner<'a> {
-    fn init(source: &'a str) -> Self {
+    pub fn init(source: &'a str) -> Self {
+        Scanner::for_file(source, 0)
+    }
+
+    pub fn for_file(source: &'a str, file: u32) -> Self {
         Scanner {
             source,
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            current_column: 1,
+            file,
+            doc: vec![],
         }
     }
 
-    fn scan_token(&mut self) -> Token<'a> {
+    // The doc comment of the token scanned last, one `///` comment per
+    // line, without the slashes and the space after them.
+    pub fn doc(&self) -> Option<String> {
+        if self.doc.is_empty() {
+            None
+        } else {
+            Some(self.doc.join("\n"))
+        }
+    }
+
+    pub fn scan_token(&mut self) -> Token<'a> {
+        self.doc.clear();
         self.skip_whitespace();
         self.start = self.current;
+        self.column = self.current_column;
         if self.is_at_end() {
             self.make_token(TokenType::Eof)
         } else {
@@ -845,7 +1581,7 @@ impl<'a> Scanner<'a> {
             if c.is_alphabetic() || c == '_' {
                 return self.identifier();
             }
-            if c.is_numeric() {
+            if c.is_ascii_digit() {
                 return self.number();
             }
 
@@ -854,8 +1590,16 @@ impl<'a> Scanner<'a> {
                 ')' => self.make_token(TokenType::RightParen),
                 '{' => self.make_token(TokenType::LeftBrace),
                 '}' => self.make_token(TokenType::RightBrace),
+                '[' => self.make_token(TokenType::LeftBracket),
+                ']' => self.make_token(TokenType::RightBracket),
                 ';' => self.make_token(TokenType::Semicolon),
                 ',' => self.make_token(TokenType::Comma),
+                ':' => self.make_token(TokenType::Colon),
+                '.' if self.peek() == '.' && self.peek_next() == Some('.') => {
+                    self.matches('.');
+                    self.matches('.');
+                    self.make_token(TokenType::DotDotDot)
+                }
                 '.' => self.make_token(TokenType::Dot),
                 '-' => self.make_token(TokenType::Minus),
                 '+' => self.make_token(TokenType::Plus),
@@ -903,14 +1647,24 @@ impl<'a> Scanner<'a> {
     }
 
     fn identifier_type(&self) -> TokenType {
-        match self.source.chars().nth(self.start).unwrap() {
+        match self.char_at(self.start) {
             'a' => self.check_keyword(1, 2, "nd", TokenType::And),
             'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
-            'd' => self.check_keyword(1, 4, "ebug", TokenType::Debug),
+            'd' => {
+                if self.current - self.start > 2 && self.char_at(self.start + 1) == 'e' {
+                    match self.char_at(self.start + 2) {
+                        'b' => self.check_keyword(3, 2, "ug", TokenType::Debug),
+                        'f' => self.check_keyword(3, 2, "er", TokenType::Defer),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
             'f' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
+                    match self.char_at(self.start + 1) {
                         'a' => self.check_keyword(2, 3, "lse", TokenType::False),
                         'o' => self.check_keyword(2, 1, "r", TokenType::For),
                         'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
@@ -920,7 +1674,17 @@ impl<'a> Scanner<'a> {
                     TokenType::Identifier
                 }
             }
-            'i' => self.check_keyword(1, 1, "f", TokenType::If),
+            'i' => {
+                if self.current - self.start > 1 {
+                    match self.char_at(self.start + 1) {
+                        'f' => self.check_keyword(2, 0, "", TokenType::If),
+                        'n' => self.check_keyword(2, 0, "", TokenType::In),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
             'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
             'o' => self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
@@ -928,7 +1692,7 @@ impl<'a> Scanner<'a> {
             's' => self.check_keyword(1, 4, "uper", TokenType::Super),
             't' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
+                    match self.char_at(self.start + 1) {
                         'h' => self.check_keyword(2, 2, "is", TokenType::This),
                         'r' => self.check_keyword(2, 2, "ue", TokenType::True),
                         _ => TokenType::Identifier,
@@ -945,7 +1709,10 @@ impl<'a> Scanner<'a> {
 
     fn check_keyword(&self, start: usize, length: usize, rest: &str, kind: TokenType) -> TokenType {
         if self.current - self.start == start + length
-            && rest == &self.source[self.start + start..self.start + start + length]
+            && self
+                .source
+                .get(self.start + start..self.start + start + length)
+                == Some(rest)
         {
             kind
         } else {
@@ -954,14 +1721,30 @@ impl<'a> Scanner<'a> {
     }
 
     fn number(&mut self) -> Token<'a> {
-        while self.peek().is_numeric() {
+        while self.peek().is_ascii_digit() {
             self.advance();
         }
 
-        if self.peek() == '.' && self.peek_next().map(|c| c.is_numeric()).unwrap_or(false) {
+        if self.peek() == '.' && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
             self.advance();
 
-            while self.peek().is_numeric() {
+            while self.peek().is_ascii_digit() {
+                self.advance();
+            }
+        } else if self.peek() == 'n' {
+            self.advance();
+            return self.make_token(TokenType::Number);
+        }
+        // an exponent, as in '1e21' or '1.5e-7', which makes a float
+        let exponent = self.source[self.current..]
+            .strip_prefix('e')
+            .map(|rest| rest.strip_prefix(|c| c == '+' || c == '-').unwrap_or(rest));
+        if exponent.is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit())) {
+            self.advance();
+            if !self.matches('+') {
+                self.matches('-');
+            }
+            while self.peek().is_ascii_digit() {
                 self.advance();
             }
         }
@@ -997,9 +1780,17 @@ impl<'a> Scanner<'a> {
                 self.advance();
             } else if c == '/' {
                 if self.peek_next() == Some('/') {
+                    let start = self.current;
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
+                    let comment = self.source[start..self.current].trim_end_matches('\r');
+                    match comment.strip_prefix("///") {
+                        Some(text) if !text.starts_with('/') => {
+                            self.doc.push(text.strip_prefix(' ').unwrap_or(text))
+                        }
+                        _ => {}
+                    }
                 } else {
                     return;
                 }
@@ -1009,17 +1800,26 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    // start and current are byte offsets into source, always on a char
+    // boundary; past the end of the source, peeking yields '\0'
+    fn char_at(&self, i: usize) -> char {
+        self.source[i..].chars().next().unwrap_or('\0')
+    }
+
     fn peek(&self) -> char {
-        self.source.chars().nth(self.current).unwrap()
+        self.char_at(self.current)
     }
 
     fn peek_next(&self) -> Option<char> {
-        self.source.chars().nth(self.current + 1)
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        chars.next()
     }
 
     fn matches(&mut self, c: char) -> bool {
-        if self.source.chars().nth(self.current) == Some(c) {
-            self.current += 1;
+        if self.source[self.current..].starts_with(c) {
+            self.current += c.len_utf8();
+            self.current_column += 1;
             true
         } else {
             false
@@ -1027,12 +1827,20 @@ impl<'a> Scanner<'a> {
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        let c = self.peek();
+        if !self.is_at_end() {
+            self.current += c.len_utf8();
+            self.current_column = if c == '\n' {
+                1
+            } else {
+                self.current_column + 1
+            };
+        }
+        c
     }
 
     fn is_at_end(&self) -> bool {
-        self.current == self.source.chars().count()
+        self.current >= self.source.len()
     }
 
     fn make_token(&self, kind: TokenType) -> Token<'a> {
@@ -1040,6 +1848,9 @@ impl<'a> Scanner<'a> {
             kind,
             lexeme: &self.source[self.start..self.current],
             line: self.line,
+            column: self.column,
+            file: self.file,
+            start: self.start,
         }
     }
 
@@ -1048,25 +1859,46 @@ impl<'a> Scanner<'a> {
             kind: TokenType::Error,
             lexeme: msg,
             line: self.line,
+            column: self.column,
+            file: self.file,
+            start: self.start,
         }
     }
 }
 
 #[derive(Clone, Copy, Debug)]
-struct Token<'a> {
+pub struct Token<'a> {
     pub kind: TokenType,
     pub lexeme: &'a str,
     pub line: usize,
+    pub column: usize,
+    // the id of its source in the `SourceMap`
+    pub file: u32,
+    pub start: usize,
+}
+
+impl Token<'_> {
+    pub fn location(&self) -> Location {
+        Location {
+            file: self.file,
+            line: self.line,
+            column: self.column,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum TokenType {
+pub enum TokenType {
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
+    DotDotDot,
     Minus,
     Plus,
     Semicolon,
@@ -1090,6 +1922,7 @@ enum TokenType {
     For,
     Fun,
     If,
+    In,
     Nil,
     Or,
     Print,
@@ -1100,6 +1933,7 @@ enum TokenType {
     Var,
     While,
     Debug,
+    Defer,
     Error,
     Eof,
 }
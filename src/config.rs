@@ -0,0 +1,76 @@
+use serde::Deserialize;
+use std::path::Path;
+use std::path::PathBuf;
+
+const FILE_NAMES: &[&str] = &["rlox.toml", ".rloxrc"];
+
+// Defaults for the command line flags, read from an rlox.toml (or .rloxrc)
+// next to the script. A flag given on the command line takes precedence:
+//
+//     warn-shadowing = true
+//     sandbox = true
+//     allow-read = true
+//     max-instructions = 1000000
+//     plugins = ["natives/libmath.so"]
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub color: Option<String>,
+    pub error_format: Option<String>,
+    pub prelude: Option<bool>,
+    pub warn_shadowing: Option<bool>,
+    pub print_exprs: Option<bool>,
+    pub shared_constants: Option<bool>,
+    pub deterministic: Option<bool>,
+    pub sandbox: Option<bool>,
+    pub allow_read: Option<bool>,
+    pub allow_net: Option<bool>,
+    pub allow_time: Option<bool>,
+    // relative paths are relative to the configuration file
+    pub plugins: Vec<PathBuf>,
+    pub checked: Option<bool>,
+    pub max_instructions: Option<u64>,
+    pub timeout: Option<u64>,
+    pub max_frames: Option<usize>,
+    pub max_stack: Option<usize>,
+    pub lenient_concat: Option<bool>,
+    pub strict_math: Option<bool>,
+}
+
+impl Config {
+    // Reads the configuration file of the directory `script` is in. A
+    // script without one gets the default, empty, configuration.
+    pub fn find(script: &Path) -> Result<Config, String> {
+        let dir = match script.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        for name in FILE_NAMES {
+            let path = dir.join(name);
+            if path.is_file() {
+                return Config::read(&path).map_err(|msg| format!("{}: {}", path.display(), msg));
+            }
+        }
+        Ok(Config::default())
+    }
+
+    fn read(path: &Path) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut config: Config = toml::from_str(&text).map_err(|e| e.message().to_string())?;
+        if let Some(color) = &config.color {
+            if !["always", "never", "auto"].contains(&color.as_str()) {
+                return Err("color must be always, never or auto".to_string());
+            }
+        }
+        if let Some(format) = &config.error_format {
+            if !["human", "json"].contains(&format.as_str()) {
+                return Err("error-format must be human or json".to_string());
+            }
+        }
+        let dir = path.parent().unwrap();
+        for plugin in config.plugins.iter_mut() {
+            *plugin = dir.join(&plugin);
+        }
+        Ok(config)
+    }
+}
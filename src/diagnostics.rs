@@ -0,0 +1,777 @@
+use std::cell::RefCell;
+use std::io::IsTerminal;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    Always,
+    Never,
+    Auto,
+}
+
+impl ColorChoice {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            "auto" => Some(ColorChoice::Auto),
+            _ => None,
+        }
+    }
+
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+                !no_color && std::io::stderr().is_terminal()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Style {
+    pub color: bool,
+}
+
+impl Style {
+    pub fn plain() -> Self {
+        Style { color: false }
+    }
+
+    pub fn error(&self, s: &str) -> String {
+        self.paint("1;31", s)
+    }
+
+    pub fn warning(&self, s: &str) -> String {
+        self.paint("1;33", s)
+    }
+
+    pub fn line(&self, s: &str) -> String {
+        self.paint("36", s)
+    }
+
+    pub fn bold(&self, s: &str) -> String {
+        self.paint("1", s)
+    }
+
+    fn paint(&self, code: &str, s: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, s)
+        } else {
+            s.to_string()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    UnterminatedString,
+    UnexpectedCharacter,
+    UnreadableFile,
+    ExpectedToken,
+    ExpectedExpression,
+    InvalidAssignmentTarget,
+    UndefinedVariable,
+    AlreadyDeclared,
+    TopLevelReturn,
+    TooManyParameters,
+    TooManyArguments,
+    DuplicateArgument,
+    DuplicateField,
+    DeferredReturn,
+    ChunkTooLarge,
+    PositionalAfterNamed,
+    OperandNotNumber,
+    OperandsNotNumbers,
+    OperandsNotAddable,
+    OperandNotBool,
+    WrongArity,
+    NativeError,
+    LimitExceeded,
+    StackOverflow,
+    ReplayDiverged,
+    Interrupted,
+    InvalidIndex,
+    CannotUnpack,
+    NotAContainer,
+    BadNamedArgument,
+    BrokenInvariant,
+    InvalidField,
+    PermissionDenied,
+    NotCallable,
+    NonFiniteNumber,
+    AssignmentInCondition,
+    ChainedComparison,
+    Shadowing,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 38] = [
+        ErrorCode::UnterminatedString,
+        ErrorCode::UnexpectedCharacter,
+        ErrorCode::UnreadableFile,
+        ErrorCode::ExpectedToken,
+        ErrorCode::ExpectedExpression,
+        ErrorCode::InvalidAssignmentTarget,
+        ErrorCode::UndefinedVariable,
+        ErrorCode::AlreadyDeclared,
+        ErrorCode::TopLevelReturn,
+        ErrorCode::TooManyParameters,
+        ErrorCode::TooManyArguments,
+        ErrorCode::DuplicateArgument,
+        ErrorCode::DuplicateField,
+        ErrorCode::DeferredReturn,
+        ErrorCode::ChunkTooLarge,
+        ErrorCode::PositionalAfterNamed,
+        ErrorCode::OperandNotNumber,
+        ErrorCode::OperandsNotNumbers,
+        ErrorCode::OperandsNotAddable,
+        ErrorCode::OperandNotBool,
+        ErrorCode::WrongArity,
+        ErrorCode::NativeError,
+        ErrorCode::LimitExceeded,
+        ErrorCode::StackOverflow,
+        ErrorCode::ReplayDiverged,
+        ErrorCode::Interrupted,
+        ErrorCode::InvalidIndex,
+        ErrorCode::CannotUnpack,
+        ErrorCode::NotAContainer,
+        ErrorCode::BadNamedArgument,
+        ErrorCode::BrokenInvariant,
+        ErrorCode::InvalidField,
+        ErrorCode::PermissionDenied,
+        ErrorCode::NotCallable,
+        ErrorCode::NonFiniteNumber,
+        ErrorCode::AssignmentInCondition,
+        ErrorCode::ChainedComparison,
+        ErrorCode::Shadowing,
+    ];
+
+    pub fn parse(code: &str) -> Option<Self> {
+        ErrorCode::ALL.iter().copied().find(|c| c.code() == code)
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorCode::UnterminatedString => "E0001",
+            ErrorCode::UnexpectedCharacter => "E0002",
+            ErrorCode::UnreadableFile => "E0003",
+            ErrorCode::ExpectedToken => "E1001",
+            ErrorCode::ExpectedExpression => "E1002",
+            ErrorCode::InvalidAssignmentTarget => "E1003",
+            ErrorCode::UndefinedVariable => "E1004",
+            ErrorCode::AlreadyDeclared => "E1005",
+            ErrorCode::TopLevelReturn => "E1006",
+            ErrorCode::TooManyParameters => "E1007",
+            ErrorCode::TooManyArguments => "E1008",
+            ErrorCode::DuplicateArgument => "E1009",
+            ErrorCode::DuplicateField => "E1010",
+            ErrorCode::DeferredReturn => "E1011",
+            ErrorCode::ChunkTooLarge => "E1012",
+            ErrorCode::PositionalAfterNamed => "E1013",
+            ErrorCode::OperandNotNumber => "E2001",
+            ErrorCode::OperandsNotNumbers => "E2002",
+            ErrorCode::OperandsNotAddable => "E2003",
+            ErrorCode::OperandNotBool => "E2004",
+            ErrorCode::WrongArity => "E2005",
+            ErrorCode::NativeError => "E2006",
+            ErrorCode::LimitExceeded => "E2007",
+            ErrorCode::StackOverflow => "E2008",
+            ErrorCode::ReplayDiverged => "E2009",
+            ErrorCode::Interrupted => "E2010",
+            ErrorCode::InvalidIndex => "E2011",
+            ErrorCode::CannotUnpack => "E2012",
+            ErrorCode::NotAContainer => "E2013",
+            ErrorCode::BadNamedArgument => "E2014",
+            ErrorCode::BrokenInvariant => "E2015",
+            ErrorCode::InvalidField => "E2016",
+            ErrorCode::PermissionDenied => "E2017",
+            ErrorCode::NotCallable => "E2018",
+            ErrorCode::NonFiniteNumber => "E2019",
+            ErrorCode::AssignmentInCondition => "W1001",
+            ErrorCode::ChainedComparison => "W1002",
+            ErrorCode::Shadowing => "W1003",
+        }
+    }
+
+    pub fn explain(self) -> &'static str {
+        match self {
+            ErrorCode::UnterminatedString => {
+                "Unterminated string.
+
+A string literal was opened with '\"' but the file ended before the
+closing quote. Strings may span several lines, so the missing quote can
+be far above the reported line.
+
+    print \"hello;  // error: the string never ends"
+            }
+            ErrorCode::UnexpectedCharacter => {
+                "Unexpected character.
+
+The scanner found a character that does not start any Lox token, such as
+'@', '#' or '%'.
+
+    var a = 1 % 2;  // error: '%' is not an operator"
+            }
+            ErrorCode::UnreadableFile => {
+                "Could not read a script.
+
+A file given to rlox does not exist, cannot be opened, or is not UTF-8
+text. With --watch, rlox keeps watching and runs the script again once
+the file can be read, as when an editor replaces it on save."
+            }
+            ErrorCode::ExpectedToken => {
+                "Expected a specific token.
+
+The parser needed a particular piece of punctuation or a name at this
+point, like the ';' closing a statement or the ')' closing a call. The
+message names the missing token.
+
+    print 1  // error: Expect ';' after value."
+            }
+            ErrorCode::ExpectedExpression => {
+                "Expect expression.
+
+An expression was required but the next token cannot start one, often
+because an operand is missing around a binary operator.
+
+    print 1 +;  // error: nothing on the right of '+'"
+            }
+            ErrorCode::InvalidAssignmentTarget => {
+                "Invalid assignment target.
+
+Only variables can appear on the left of '='. The left-hand side here is
+an expression that cannot be assigned to.
+
+    var a = 1;
+    a + 1 = 2;  // error: 'a + 1' is not a variable"
+            }
+            ErrorCode::UndefinedVariable => {
+                "Undefined variable.
+
+The name does not refer to any variable or function visible from this
+scope. Variables must be declared with 'var' (or 'fun') before being used.
+
+    {
+      var count = 1;
+    }
+    print count;  // error: 'count' went out of scope with the block"
+            }
+            ErrorCode::AlreadyDeclared => {
+                "Variable already declared in this scope.
+
+A block cannot declare the same name twice. Use assignment to change the
+existing variable, or open a new block to shadow it.
+
+    {
+      var a = 1;
+      var a = 2;  // error: use 'a = 2;' instead
+    }"
+            }
+            ErrorCode::TopLevelReturn => {
+                "Return from top-level code.
+
+'return' is only allowed inside a function body; a script cannot return
+a value.
+
+    return 1;  // error: not inside a function"
+            }
+            ErrorCode::TooManyParameters => {
+                "Too many parameters.
+
+A function can declare at most 255 parameters. Group related values
+together if you need more."
+            }
+            ErrorCode::TooManyArguments => {
+                "Too many arguments.
+
+A call can pass at most 255 arguments, matching the parameter limit."
+            }
+            ErrorCode::DuplicateArgument => {
+                "Repeated named argument.
+
+Each name can be given only once per call.
+
+    plot(x: 1, y: 2);  // ok
+    plot(x: 1, x: 2);  // error: 'x' given twice"
+            }
+            ErrorCode::DuplicateField => {
+                "Repeated field in a record literal.
+
+Each field of a record literal can be given only once.
+
+    var p = {x: 1, y: 2};  // ok
+    var q = {x: 1, x: 2};  // error: 'x' given twice"
+            }
+            ErrorCode::DeferredReturn => {
+                "Return from a deferred statement.
+
+A deferred statement runs when its block exits, including when the
+function returns, so it cannot return itself.
+
+    fun f() {
+      defer return 1;  // error: 'return' inside 'defer'
+    }"
+            }
+            ErrorCode::ChunkTooLarge => {
+                "Function too large to compile.
+
+The bytecode of one function, or of the top-level script, outgrew the
+compiler's limits on its size or on its number of constants. Split it
+into smaller functions."
+            }
+            ErrorCode::PositionalAfterNamed => {
+                "Positional argument after a named argument.
+
+Named arguments ('name: value') come after all positional arguments of
+a call, since the positional ones fill the parameters in order.
+
+    plot(1, y: 2);  // ok
+    plot(x: 1, 2);  // error: '2' comes after 'x: 1'"
+            }
+            ErrorCode::OperandNotNumber => {
+                "Operand must be a number.
+
+Unary '-' only applies to numbers.
+
+    print -\"a\";  // error: cannot negate a string"
+            }
+            ErrorCode::OperandsNotNumbers => {
+                "Operands must be numbers.
+
+'-', '*', '/', '<', '<=', '>' and '>=' require both operands to be
+numbers. Only '+' also accepts strings.
+
+    print \"a\" * 2;  // error: cannot multiply a string"
+            }
+            ErrorCode::OperandsNotAddable => {
+                "Operands must be two numbers or two strings.
+
+'+' adds two numbers or concatenates two strings. Mixing the two kinds
+is an error, unless rlox runs with --lenient-concat. Otherwise, convert
+the number with str().
+
+    print \"count: \" + 1;       // error: string plus number
+    print \"count: \" + str(1);  // ok"
+            }
+            ErrorCode::OperandNotBool => {
+                "Operand must be a bool.
+
+'!' only applies to 'true' and 'false'.
+
+    print !nil;  // error: nil is not a bool"
+            }
+            ErrorCode::WrongArity => {
+                "Wrong number of arguments.
+
+A function was called with a different number of arguments than it
+declares parameters.
+
+    fun add(a, b) { return a + b; }
+    add(1);  // error: add() expects 2 arguments but got 1."
+            }
+            ErrorCode::NativeError => {
+                "Native function failed.
+
+A function implemented in Rust, or loaded from a plugin, rejected its
+arguments or failed while running. The message comes from the native
+function itself."
+            }
+            ErrorCode::LimitExceeded => {
+                "Execution limit exceeded.
+
+The script ran for longer than the VM was configured to allow, either in
+executed instructions (--max-instructions) or in wall-clock time
+(--timeout). This usually means an infinite loop.
+
+    while (true) {}  // error with --max-instructions=1000"
+            }
+            ErrorCode::StackOverflow => {
+                "Stack overflow.
+
+A call would nest deeper than the VM allows (--max-frames) or the value
+stack grew past its limit (--max-stack). This is almost always a
+recursive function missing its base case.
+
+    fun loop(n) { return loop(n + 1); }
+    loop(0);  // error: never stops recursing"
+            }
+            ErrorCode::ReplayDiverged => {
+                "Replay diverged from the recorded trace.
+
+A script run with --replay executed a different instruction or call
+than the one recorded by --record, or the trace file could not be
+written or read. Replays must use the same script and options as the
+recording; only native results are taken from the trace.
+
+    rlox --record t.bin a.lox
+    rlox --replay t.bin b.lox  // error: b.lox runs different code"
+            }
+            ErrorCode::Interrupted => {
+                "Interrupted.
+
+Ctrl+C was pressed while the script was running, or the application
+embedding rlox cancelled it. The VM stops at the next instruction and
+prints where it was, which helps locate an infinite loop.
+
+    while (true) {}  // press Ctrl+C: error with a stack trace"
+            }
+            ErrorCode::InvalidIndex => {
+                "Invalid index.
+
+Only strings, tuples and bytes can be indexed, with an integer between 0
+and their length minus one. Strings are indexed by Unicode code point, and
+'s[i]' returns the character at 'i' as a one-character string. Bytes are
+indexed by byte, which is returned as an integer from 0 to 255.
+
+    print \"héllo\"[1];  // é
+    print (1, 2)[1];   // 2
+    print \"abc\"[3];    // error: out of range
+    print \"abc\"[0.5];  // error: not an integer"
+            }
+            ErrorCode::CannotUnpack => {
+                "Cannot unpack value.
+
+A destructuring declaration needs a tuple with exactly as many elements
+as there are names on the left, and only tuples can be spread into a
+call or a tuple with '...'.
+
+    var (x, y) = (1, 2);     // ok
+    var (a, b) = (1, 2, 3);  // error: expected a tuple of 2 values
+    var (c, d) = 12;         // error: not a tuple
+    print max(...(1, 2));    // ok
+    print max(...12);        // error: not a tuple"
+            }
+            ErrorCode::NotAContainer => {
+                "Right operand of 'in' is not a container.
+
+'x in t' tests whether the tuple 't' has an element equal to 'x', and
+'sub in s' whether the string 's' contains the string 'sub'. Any other
+combination of operands is an error.
+
+    print 2 in (1, 2, 3);       // true
+    print \"ell\" in \"hello\";    // true
+    print 1 in \"123\";          // error: 1 is not a string"
+            }
+            ErrorCode::BadNamedArgument => {
+                "Named arguments do not match the parameters.
+
+Each named argument must name a parameter of the called function that
+no positional argument already fills, and every parameter must end up
+with a value. Natives have no parameter names, so only Lox functions
+accept named arguments.
+
+    fun plot(x, y) {}
+    plot(y: 2, x: 1);  // ok
+    plot(1, x: 2);     // error: 'x' given twice
+    plot(x: 1);        // error: missing 'y'
+    plot(z: 1, y: 2);  // error: no parameter 'z'"
+            }
+            ErrorCode::BrokenInvariant => {
+                "The VM found its own state inconsistent.
+
+In checked mode (--checked, and always in debug builds) the VM validates
+every instruction's operands and effect on the stack, and every frame it
+enters or leaves. This error means the bytecode or the VM state is
+corrupt, which is a bug in rlox rather than in the script: please report
+it along with the script that triggered it."
+            }
+            ErrorCode::InvalidField => {
+                "Invalid field access.
+
+Only records have fields, and a record only has the fields its literal
+gave it. Records cannot be changed once created.
+
+    var p = {x: 1, y: 2};
+    print p.x;        // 1
+    print p.z;        // error: no field 'z'
+    print \"abc\".x;  // error: strings have no fields"
+            }
+            ErrorCode::PermissionDenied => {
+                "Permission denied.
+
+A native that reads files, reaches the network or reads the clock was
+called, but the VM was not given that permission. The CLI grants every
+permission unless it is run with --sandbox; --allow-read, --allow-net
+and --allow-time grant them back one by one. Applications embedding
+rlox grant none unless they set VmConfig::permissions.
+
+    // rlox --sandbox script.lox
+    print clock();  // error: clock() needs the 'time' permission"
+            }
+            ErrorCode::NotCallable => {
+                "Calling a value that is not a function.
+
+Only Lox functions and natives can be called.
+
+    var n = 1;
+    n();  // error: a number is not callable"
+            }
+            ErrorCode::NonFiniteNumber => {
+                "Arithmetic gave infinity or NaN.
+
+With --strict-math, '+', '-', '*' and '/' of finite numbers must give a
+finite number: dividing by zero, overflowing to infinity and producing
+NaN are runtime errors instead of results.
+
+    print 1 / 0;      // error: division by zero
+    print 1e308 * 10; // error: overflow to infinity"
+            }
+            ErrorCode::AssignmentInCondition => {
+                "Assignment used as a condition.
+
+The condition of an 'if', 'while' or 'for' is a bare assignment, which
+is usually a typo for '=='. The assignment still runs and its value is
+tested, so the code compiles, but this warning is reported.
+
+    if (a = b) print a;    // warning: did you mean 'a == b'?
+    if ((a = b)) print a;  // no warning: parentheses mark it as intended"
+            }
+            ErrorCode::ChainedComparison => {
+                "Comparison chained to another comparison.
+
+Comparisons do not chain in Lox: 'a < b < c' means '(a < b) < c', which
+compares the boolean result of 'a < b' to a number and fails at runtime.
+Compare each pair and join them with 'and'.
+
+    print 1 < x < 10;           // warning
+    print 1 < x and x < 10;     // no warning"
+            }
+            ErrorCode::Shadowing => {
+                "Declaration shadows an outer variable.
+
+A local variable or parameter has the same name as a variable from an
+enclosing block or function, which hides the outer one until the end of
+the scope. This lint is off by default; enable it with --warn-shadowing.
+
+    var count = 0;
+    fun add(count) {    // warning: shadows the 'count' on line 1
+      print count;
+    }"
+            }
+        }
+    }
+}
+
+// How many calls of a runtime error's stack trace are printed.
+const TRACE_FRAMES: usize = 16;
+
+// A reported error or warning. The compiler and the VM hand every
+// diagnostic to a `Reporter` rather than printing it themselves.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub code: ErrorCode,
+    // the name of the file, when the reporter was told it
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    // the token a compile error was found at
+    pub at: Option<At>,
+    // the calls active when a runtime error happened, innermost first
+    pub frames: Vec<Frame>,
+}
+
+// A call active when a runtime error happened, and the line it was
+// executing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub function: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum At {
+    End,
+    Lexeme(String),
+}
+
+impl Diagnostic {
+    pub fn is_warning(&self) -> bool {
+        self.code.code().starts_with('W')
+    }
+}
+
+pub trait Reporter {
+    fn report(&self, diagnostic: &Diagnostic);
+}
+
+// 'script.lox:3:7', or 'line 3' when the file has no name.
+fn position(file: &Option<String>, line: usize, column: usize) -> String {
+    match file {
+        // about the file as a whole
+        Some(file) if line == 0 => file.clone(),
+        Some(file) => format!("{}:{}:{}", file, line, column),
+        None => format!("line {}", line),
+    }
+}
+
+fn line_label(file: &Option<String>, line: usize, column: usize) -> String {
+    format!("[{}]", position(file, line, column))
+}
+
+// Prints diagnostics to stderr the way rlox always has.
+pub struct HumanReporter {
+    pub style: Style,
+}
+
+impl Reporter for HumanReporter {
+    fn report(&self, diagnostic: &Diagnostic) {
+        let style = &self.style;
+        let label = if diagnostic.is_warning() {
+            style.warning(&format!("Warning[{}]", diagnostic.code.code()))
+        } else {
+            style.error(&format!("Error[{}]", diagnostic.code.code()))
+        };
+        if diagnostic.frames.is_empty() {
+            let line = line_label(&diagnostic.file, diagnostic.line, diagnostic.column);
+            let at = match &diagnostic.at {
+                Some(At::End) => " at end".to_string(),
+                Some(At::Lexeme(lexeme)) => format!(" at {}", style.bold(lexeme)),
+                None => String::new(),
+            };
+            eprintln!(
+                "{} {}{}: {}",
+                style.line(&line),
+                label,
+                at,
+                diagnostic.message
+            );
+            return;
+        }
+        eprintln!("{}: {}", label, diagnostic.message);
+        for frame in diagnostic.frames.iter().take(TRACE_FRAMES) {
+            let position = position(&frame.file, frame.line, frame.column);
+            eprintln!("    at {} ({})", frame.function, style.line(&position));
+        }
+        if diagnostic.frames.len() > TRACE_FRAMES {
+            eprintln!("    … {} more", diagnostic.frames.len() - TRACE_FRAMES);
+        }
+    }
+}
+
+// Prints each diagnostic to stderr as one JSON object per line, for
+// editors and other tools.
+pub struct JsonReporter;
+
+impl Reporter for JsonReporter {
+    fn report(&self, diagnostic: &Diagnostic) {
+        let severity = if diagnostic.is_warning() {
+            "warning"
+        } else {
+            "error"
+        };
+        let at = match &diagnostic.at {
+            Some(At::End) => ",\"at_end\":true".to_string(),
+            Some(At::Lexeme(lexeme)) => format!(",\"at\":{}", json_string(lexeme)),
+            None => String::new(),
+        };
+        let frames: Vec<String> = diagnostic
+            .frames
+            .iter()
+            .map(|frame| {
+                format!(
+                    "{{{}\"line\":{},\"column\":{},\"function\":{}}}",
+                    json_file(&frame.file),
+                    frame.line,
+                    frame.column,
+                    json_string(&frame.function)
+                )
+            })
+            .collect();
+        eprintln!(
+            "{{\"severity\":\"{}\",\"code\":\"{}\",{}\"line\":{},\"column\":{}{},\"message\":{},\"frames\":[{}]}}",
+            severity,
+            diagnostic.code.code(),
+            json_file(&diagnostic.file),
+            diagnostic.line,
+            diagnostic.column,
+            at,
+            json_string(&diagnostic.message),
+            frames.join(",")
+        );
+    }
+}
+
+// The "file" member of a diagnostic or frame, if its file is known.
+fn json_file(file: &Option<String>) -> String {
+    match file {
+        Some(file) => format!("\"file\":{},", json_string(file)),
+        None => String::new(),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub struct SilentReporter;
+
+impl Reporter for SilentReporter {
+    fn report(&self, _diagnostic: &Diagnostic) {}
+}
+
+// Keeps the diagnostics for the embedder to inspect. Share it with the
+// compiler or VM through an `Rc` and `take` them once it is done.
+#[derive(Default)]
+pub struct CollectingReporter {
+    diagnostics: RefCell<Vec<Diagnostic>>,
+}
+
+impl CollectingReporter {
+    pub fn take(&self) -> Vec<Diagnostic> {
+        self.diagnostics.take()
+    }
+}
+
+impl Reporter for CollectingReporter {
+    fn report(&self, diagnostic: &Diagnostic) {
+        self.diagnostics.borrow_mut().push(diagnostic.clone());
+    }
+}
+
+pub fn suggest<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let threshold = std::cmp::max(1, name.chars().count() / 3);
+    candidates
+        .iter()
+        .filter(|candidate| **candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
@@ -0,0 +1,42 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Returns whether Ctrl+C was pressed since the last call. The VM polls this
+// from its dispatch loop and stops with a runtime error. A plain load comes
+// first, as it is much cheaper than the swap on every instruction.
+pub fn requested() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed) && INTERRUPTED.swap(false, Ordering::Relaxed)
+}
+
+// Runs `f` with a SIGINT handler that sets the interrupt flag instead of
+// killing the process. The default handler is restored afterwards, so
+// Ctrl+C outside of a run (e.g. while --watch waits for changes) still exits.
+#[cfg(unix)]
+pub fn catch<T, F: FnOnce() -> T>(f: F) -> T {
+    use std::os::raw::c_int;
+
+    const SIGINT: c_int = 2;
+    const SIG_DFL: usize = 0;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn handle(_: c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+
+    INTERRUPTED.store(false, Ordering::Relaxed);
+    let handler: extern "C" fn(c_int) = handle;
+    unsafe { signal(SIGINT, handler as usize) };
+    let result = f();
+    unsafe { signal(SIGINT, SIG_DFL) };
+    result
+}
+
+#[cfg(not(unix))]
+pub fn catch<T, F: FnOnce() -> T>(f: F) -> T {
+    f()
+}
@@ -0,0 +1,14 @@
+pub mod asm;
+pub mod chunk;
+pub mod compiler;
+pub mod config;
+pub mod diagnostics;
+pub mod interrupt;
+pub mod lox;
+pub mod minifier;
+pub mod natives;
+#[cfg(feature = "plugins")]
+pub mod plugins;
+pub mod stats;
+pub mod trace;
+pub mod vm;
@@ -0,0 +1,133 @@
+use crate::chunk::Function;
+use crate::chunk::Native;
+use crate::compiler::CompileLimits;
+use crate::compiler::Parser;
+use crate::diagnostics::CollectingReporter;
+use crate::diagnostics::Diagnostic;
+use crate::natives;
+use crate::vm::InterpretResult;
+use crate::vm::VmConfig;
+use crate::vm::VM;
+use std::rc::Rc;
+
+// Entry point for embedding rlox: holds the natives and VM limits
+// shared by every script it runs.
+#[derive(Clone, Debug)]
+pub struct Lox {
+    pub natives: Vec<Native>,
+    pub prelude: bool,
+    pub limits: CompileLimits,
+    pub config: VmConfig,
+}
+
+// A compiled script. It is immutable, so it can be run any number of
+// times, each run starting from a fresh VM.
+#[derive(Clone, Debug)]
+pub struct Program {
+    script: Rc<Function>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitKind {
+    Ok,
+    CompileError,
+    RuntimeError,
+    LimitExceeded,
+    Interrupted,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunOutcome {
+    pub stdout: String,
+    pub diagnostics: Vec<Diagnostic>,
+    pub result: ExitKind,
+}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Lox {
+            natives: natives::standard(),
+            prelude: true,
+            limits: CompileLimits::default(),
+            config: VmConfig::default(),
+        }
+    }
+}
+
+impl Lox {
+    pub fn new() -> Self {
+        Lox::default()
+    }
+
+    pub fn compile(&self, source: &str) -> Result<Program, Vec<Diagnostic>> {
+        let mut parser = Parser::init(source);
+        parser.natives = self.natives.clone();
+        parser.limits = self.limits;
+        let collected = Rc::new(CollectingReporter::default());
+        parser.reporter = collected.clone();
+        if self.prelude {
+            parser.load_prelude();
+        }
+        let script = parser.compile();
+        let diagnostics = collected.take();
+        match script {
+            Some(script) => Ok(Program {
+                script: Rc::new(script),
+                warnings: diagnostics,
+            }),
+            None => Err(diagnostics),
+        }
+    }
+
+    pub fn run(&self, program: &Program) -> ExitKind {
+        VM::new(program.script.clone(), self.config.clone())
+            .run()
+            .into()
+    }
+
+    // Runs `program`, collecting what it prints and any runtime error
+    // instead of writing them to stdout and stderr.
+    pub fn run_capture(&self, program: &Program) -> RunOutcome {
+        let mut vm = VM::new(program.script.clone(), self.config.clone());
+        vm.output = Some(String::new());
+        let collected = Rc::new(CollectingReporter::default());
+        vm.reporter = collected.clone();
+        let result = vm.run().into();
+        RunOutcome {
+            stdout: vm.output.take().unwrap_or_default(),
+            diagnostics: collected.take(),
+            result,
+        }
+    }
+
+    // Like `run_capture`, with the compiler's warnings and errors
+    // reported as diagnostics too.
+    pub fn run_source_capture(&self, source: &str) -> RunOutcome {
+        match self.compile(source) {
+            Ok(program) => {
+                let mut outcome = self.run_capture(&program);
+                let mut diagnostics = program.warnings;
+                diagnostics.append(&mut outcome.diagnostics);
+                outcome.diagnostics = diagnostics;
+                outcome
+            }
+            Err(diagnostics) => RunOutcome {
+                stdout: String::new(),
+                diagnostics,
+                result: ExitKind::CompileError,
+            },
+        }
+    }
+}
+
+impl From<InterpretResult> for ExitKind {
+    fn from(result: InterpretResult) -> Self {
+        match result {
+            InterpretResult::Ok => ExitKind::Ok,
+            InterpretResult::RuntimeError => ExitKind::RuntimeError,
+            InterpretResult::LimitExceeded => ExitKind::LimitExceeded,
+            InterpretResult::Interrupted => ExitKind::Interrupted,
+        }
+    }
+}
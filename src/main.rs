@@ -1,348 +1,555 @@
-use crate::chunk::Closure;
-use crate::chunk::OpCode;
-use crate::chunk::UpValue;
-use crate::chunk::Value;
-use crate::compiler::Parser;
-use std::cell::RefCell;
-use std::convert::TryInto;
-use std::env::args;
+use clap::CommandFactory;
+use clap::Parser as _;
+use log::debug;
+use rlox::asm;
+use rlox::chunk::DisassemblyOptions;
+use rlox::chunk::Function;
+use rlox::chunk::Native;
+use rlox::chunk::Permissions;
+use rlox::chunk::SourceMap;
+use rlox::compiler::Parser;
+use rlox::config::Config;
+use rlox::diagnostics::ColorChoice;
+use rlox::diagnostics::Diagnostic;
+use rlox::diagnostics::ErrorCode;
+use rlox::diagnostics::HumanReporter;
+use rlox::diagnostics::JsonReporter;
+use rlox::diagnostics::Reporter;
+use rlox::diagnostics::Style;
+use rlox::interrupt;
+use rlox::minifier;
+use rlox::natives;
+#[cfg(feature = "plugins")]
+use rlox::plugins;
+use rlox::stats::Stats;
+use rlox::trace::Trace;
+use rlox::vm::InterpretResult;
+use rlox::vm::VmConfig;
+use rlox::vm::VM;
+use std::io::Write;
+use std::path::Path;
 use std::rc::Rc;
+use std::time::Duration;
 
-mod chunk;
-mod compiler;
+#[derive(Clone, Debug)]
+struct Options {
+    style: Style,
+    json_errors: bool,
+    stats: bool,
+    prelude: bool,
+    warn_shadowing: bool,
+    print_exprs: bool,
+    shared_constants: bool,
+    natives: Vec<Native>,
+    config: VmConfig,
+    record: Option<String>,
+    replay: Option<String>,
+}
 
-#[derive(Debug)]
-struct CallStack {
-    closure: Closure,
-    ip: usize,
-    offset: usize,
+// The command line. Flags that affect compilation apply to every
+// subcommand; running a script without a subcommand is `rlox run`.
+#[derive(clap::Parser)]
+#[command(name = "rlox", version, about = "A bytecode virtual machine for Lox")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    compile: CompileArgs,
+    #[command(flatten)]
+    run: RunArgs,
+    /// The script to run, or the files to compile into it in order
+    scripts: Vec<String>,
 }
 
-struct VM {
-    frames: Vec<CallStack>,
-    stack: Vec<Value>,
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Run a script, compiled from the given files in order
+    Run {
+        #[command(flatten)]
+        run: RunArgs,
+        #[arg(required = true)]
+        scripts: Vec<String>,
+    },
+    /// Report a script's errors and warnings without running it
+    Check {
+        #[arg(required = true)]
+        scripts: Vec<String>,
+    },
+    /// Print a script with the shortest names and whitespace possible
+    Minify { script: String },
+    /// Print the bytecode of every function of a script
+    Dis {
+        /// Show the byte offset of each instruction
+        #[arg(long)]
+        offsets: bool,
+        /// Show each function's constant pool
+        #[arg(long)]
+        constants: bool,
+        /// Show the source line of each instruction
+        #[arg(long)]
+        lines: bool,
+        /// Print assembly that `rlox asm` can run instead
+        #[arg(long, conflicts_with_all = ["offsets", "constants", "lines"])]
+        emit_asm: bool,
+        script: String,
+    },
+    /// Assemble a script written by `rlox dis --emit-asm` and run it
+    Asm {
+        #[command(flatten)]
+        run: RunArgs,
+        script: String,
+    },
+    /// Explain an error code, e.g. E2001
+    Explain { code: String },
 }
 
-enum InterpretResult {
-    Ok,
-    RuntimeError,
+#[derive(clap::Args)]
+struct CompileArgs {
+    /// Colorize diagnostics [default: auto]
+    #[arg(long, global = true, value_name = "WHEN", value_parser = parse_color)]
+    color: Option<ColorChoice>,
+    /// How to print errors and warnings [default: human]
+    #[arg(long, global = true, value_name = "FORMAT", value_parser = ["human", "json"])]
+    error_format: Option<String>,
+    /// Do not load the built-in Lox prelude
+    #[arg(long, global = true)]
+    no_prelude: bool,
+    /// Warn when a declaration hides an outer variable
+    #[arg(long, global = true)]
+    warn_shadowing: bool,
+    /// Print the value of every expression statement, with its line
+    #[arg(long, global = true)]
+    print_exprs: bool,
+    /// Give all functions one constant pool, storing each constant once
+    #[arg(long, global = true)]
+    shared_constants: bool,
+    /// Use a logical clock for clock() and now()
+    #[arg(long, global = true)]
+    deterministic: bool,
+    /// Deny natives access to files, the network and the clock
+    #[arg(long, global = true)]
+    sandbox: bool,
+    /// Let readFileBytes() run in the sandbox
+    #[arg(long, global = true)]
+    allow_read: bool,
+    /// Let httpGet() and httpPost() run in the sandbox
+    #[arg(long, global = true)]
+    allow_net: bool,
+    /// Let clock() and now() run in the sandbox
+    #[arg(long, global = true)]
+    allow_time: bool,
+    /// Load native functions from a shared library
+    #[arg(long, global = true, value_name = "LIBRARY")]
+    plugin: Vec<String>,
 }
 
-impl VM {
-    fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
-    }
+#[derive(clap::Args, Default)]
+struct RunArgs {
+    /// Run the script again whenever it changes
+    #[arg(long)]
+    watch: bool,
+    /// Print executed instruction counts at exit
+    #[arg(long)]
+    stats: bool,
+    /// Validate VM invariants (default in debug builds)
+    #[arg(long)]
+    checked: bool,
+    /// Abort after executing N instructions
+    #[arg(long, value_name = "N")]
+    max_instructions: Option<u64>,
+    /// Abort after running for MS milliseconds
+    #[arg(long, value_name = "MS")]
+    timeout: Option<u64>,
+    /// Allow at most N nested calls [default: 1024]
+    #[arg(long, value_name = "N")]
+    max_frames: Option<usize>,
+    /// Allow at most N values on the stack
+    #[arg(long, value_name = "N")]
+    max_stack: Option<usize>,
+    /// Let '+' concatenate a string and a number
+    #[arg(long)]
+    lenient_concat: bool,
+    /// Fail on division by zero and on arithmetic giving infinity or NaN
+    #[arg(long)]
+    strict_math: bool,
+    /// Record the execution to a trace file
+    #[arg(long, value_name = "TRACE", conflicts_with = "replay")]
+    record: Option<String>,
+    /// Re-run a recorded trace, reusing its native results
+    #[arg(long, value_name = "TRACE")]
+    replay: Option<String>,
+}
+
+fn parse_color(s: &str) -> Result<ColorChoice, String> {
+    ColorChoice::parse(s).ok_or_else(|| "expected always, never or auto".to_string())
+}
+
+fn main() {
+    env_logger::init();
+    // usage errors exit with 64 like every other bad invocation of rlox
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        let _ = e.print();
+        std::process::exit(if e.use_stderr() { 64 } else { 0 });
+    });
+    let command = match cli.command {
+        Some(command) => command,
+        None if !cli.scripts.is_empty() => Command::Run {
+            run: cli.run,
+            scripts: cli.scripts,
+        },
+        None => {
+            let _ = Cli::command().print_help();
+            std::process::exit(64);
+        }
+    };
+    let compile = cli.compile;
+    let no_run = RunArgs::default();
 
-    fn push(&mut self, v: Value) {
-        self.stack.push(v);
+    match command {
+        Command::Run { run, scripts } => {
+            let options = options(compile, &run, &scripts[0]);
+            if run.watch {
+                watch_files(scripts, options)
+            }
+            std::process::exit(run_files(scripts, options))
+        }
+        Command::Check { scripts } => {
+            let options = options(compile, &no_run, &scripts[0]);
+            check_files(scripts, options)
+        }
+        Command::Minify { script } => {
+            let options = options(compile, &no_run, &script);
+            minify_file(script, options)
+        }
+        Command::Dis {
+            offsets,
+            constants,
+            lines,
+            emit_asm,
+            script,
+        } => {
+            let disassembly = DisassemblyOptions {
+                offsets,
+                constants,
+                lines,
+            };
+            let options = options(compile, &no_run, &script);
+            if emit_asm {
+                emit_asm_file(script, options)
+            } else {
+                disassemble_file(script, disassembly, options)
+            }
+        }
+        Command::Asm { run, script } => {
+            let options = options(compile, &run, &script);
+            std::process::exit(assemble_file(script, options))
+        }
+        Command::Explain { code } => explain(code),
     }
+}
 
-    fn frame(&self) -> &CallStack {
-        self.frames.last().unwrap()
+// Combines the command line with the configuration file next to `script`,
+// the command line taking precedence.
+fn options(compile: CompileArgs, run: &RunArgs, script: &str) -> Options {
+    let defaults = Config::find(Path::new(script)).unwrap_or_else(|msg| {
+        eprintln!("Invalid configuration {}", msg);
+        std::process::exit(78);
+    });
+    let mut natives = natives::standard();
+    natives.extend(natives::filesystem());
+    add_network(&mut natives);
+    for path in &defaults.plugins {
+        load_plugin(&path.to_string_lossy(), &mut natives);
     }
+    for path in &compile.plugin {
+        load_plugin(path, &mut natives);
+    }
+    if compile.deterministic || defaults.deterministic == Some(true) {
+        natives::make_deterministic(&mut natives);
+    }
+    let mut config = VmConfig::default();
+    config.checked = run.checked || defaults.checked.unwrap_or(config.checked);
+    config.max_instructions = run.max_instructions.or(defaults.max_instructions);
+    config.max_wall_time = run.timeout.or(defaults.timeout).map(Duration::from_millis);
+    if let Some(max) = run.max_frames.or(defaults.max_frames) {
+        config.max_frames = max;
+    }
+    if let Some(max) = run.max_stack.or(defaults.max_stack) {
+        config.max_stack = max;
+    }
+    config.lenient_concat = run.lenient_concat || defaults.lenient_concat == Some(true);
+    config.strict_math = run.strict_math || defaults.strict_math == Some(true);
+    config.permissions = if compile.sandbox || defaults.sandbox == Some(true) {
+        Permissions {
+            fs: compile.allow_read || defaults.allow_read == Some(true),
+            net: compile.allow_net || defaults.allow_net == Some(true),
+            time: compile.allow_time || defaults.allow_time == Some(true),
+        }
+    } else {
+        Permissions::all()
+    };
+    let color = compile
+        .color
+        .or_else(|| defaults.color.as_deref().and_then(ColorChoice::parse))
+        .unwrap_or(ColorChoice::Auto);
+    let error_format = compile.error_format.or(defaults.error_format);
+    Options {
+        style: Style {
+            color: color.enabled(),
+        },
+        json_errors: error_format.as_deref() == Some("json"),
+        stats: run.stats,
+        prelude: !compile.no_prelude && defaults.prelude.unwrap_or(true),
+        warn_shadowing: compile.warn_shadowing || defaults.warn_shadowing == Some(true),
+        print_exprs: compile.print_exprs || defaults.print_exprs == Some(true),
+        shared_constants: compile.shared_constants || defaults.shared_constants == Some(true),
+        natives,
+        config,
+        record: run.record.clone(),
+        replay: run.replay.clone(),
+    }
+}
 
-    fn frame_mut(&mut self) -> &mut CallStack {
-        self.frames.last_mut().unwrap()
+#[cfg(feature = "plugins")]
+fn load_plugin(path: &str, natives: &mut Vec<Native>) {
+    if let Err(msg) = plugins::load(path, natives) {
+        eprintln!("Could not load plugin '{}': {}", path, msg);
+        std::process::exit(74);
     }
+}
 
-    fn run(&mut self) -> InterpretResult {
-        loop {
-            let instruction = self.frame().closure.function.chunk.code[self.frame().ip];
-            self.frame_mut().ip += 1;
-            match instruction.into() {
-                OpCode::Return => {
-                    let v = self.pop();
-                    let frame = self.frames.pop().unwrap();
-                    // here lies our garbage collector!
-                    if self.frames.is_empty() {
-                        self.stack.truncate(0);
-                        return InterpretResult::Ok;
-                    }
-                    self.stack.truncate(frame.offset - 1);
-                    self.push(v);
-                }
-                OpCode::Constant => {
-                    let index = self.read_u32();
-                    let constant =
-                        (&self.frame().closure.function.chunk.constants[index as usize]).clone();
-                    self.push(constant);
-                }
-                OpCode::Closure => {
-                    let index = self.read_u32();
-                    let function = (&self.frame().closure.function.chunk.constants[index as usize])
-                        .clone()
-                        .as_function();
-                    let mut upvalues = vec![];
-                    for _ in 0..function.upvalue_count {
-                        let is_local = self.read_bool();
-                        let index = self.read_u32();
-                        if is_local {
-                            upvalues
-                                .push(self.capture_upvalue(self.frame().offset + index as usize));
-                        } else {
-                            upvalues.push(self.frame().closure.upvalues[index as usize].clone());
-                        }
-                    }
-                    let closure_value = Value::closure(function, upvalues);
-                    self.push(closure_value);
-                }
-                OpCode::Divide => {
-                    if !self.peek(0).is_number() || !self.peek(0).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(Value::from_number(a.as_number() / b.as_number()));
-                }
-                OpCode::Add => {
-                    if self.peek(0).is_string() && self.peek(1).is_string() {
-                        self.concatenate();
-                    } else if self.peek(0).is_number() && self.peek(1).is_number() {
-                        let b = self.pop();
-                        let a = self.pop();
-                        self.push(Value::from_number(a.as_number() + b.as_number()));
-                    } else {
-                        self.runtime_error(&format!(
-                            "Operands must be two numbers or two strings. Received: {} and {}.",
-                            self.peek(0).print(),
-                            self.peek(1).print()
-                        ));
-                        return InterpretResult::RuntimeError;
-                    }
-                }
-                OpCode::Negate => {
-                    if !self.peek(0).is_number() {
-                        self.runtime_error("Operand must be a number.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let neg = self.pop();
-                    self.push(Value::from_number(-neg.as_number()));
-                }
-                OpCode::Multiply => {
-                    if !self.peek(0).is_number() || !self.peek(0).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(Value::from_number(a.as_number() * b.as_number()));
-                }
-                OpCode::Substract => {
-                    if !self.peek(0).is_number() || !self.peek(0).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(Value::from_number(a.as_number() - b.as_number()));
-                }
-                OpCode::Not => {
-                    if !self.peek(0).is_bool() {
-                        self.runtime_error("Operand must be a bool.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let b = self.pop();
-                    self.push(Value::from_bool(!b.as_bool()));
-                }
-                OpCode::Equal => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(Value::from_bool(a == b));
-                }
-                OpCode::Less => {
-                    if !self.peek(0).is_number() || !self.peek(0).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(Value::from_bool(a.as_number() < b.as_number()));
-                }
-                OpCode::Greater => {
-                    if !self.peek(0).is_number() || !self.peek(0).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(Value::from_bool(a.as_number() > b.as_number()));
-                }
-                OpCode::Print => {
-                    println!("{}", self.pop().print());
-                }
-                OpCode::Nil => {
-                    self.push(Value::Nil);
-                }
-                OpCode::Pop => {
-                    self.pop();
-                }
-                OpCode::JumpIfFalse => {
-                    let jump = self.read_u32();
-                    if !self.peek(0).as_bool() {
-                        self.frame_mut().ip += jump as usize;
-                    }
-                }
-                OpCode::Jump => {
-                    let jump = self.read_u32();
-                    self.frame_mut().ip += jump as usize;
-                }
-                OpCode::Loop => {
-                    let jump = self.read_u32();
-                    self.frame_mut().ip -= jump as usize;
-                }
-                OpCode::GetLocal => {
-                    let index = self.read_u32();
-                    self.push(self.stack[self.frame().offset + index as usize].clone());
-                }
-                OpCode::SetLocal => {
-                    let index = self.read_u32();
-                    let offset = self.frame().offset;
-                    let value = self.peek(0).clone();
-                    self.stack[offset + index as usize] = value;
-                }
-                OpCode::GetUpvalue => {
-                    let slot = self.read_u32();
-                    self.push(Value::Lifted(
-                        self.frame().closure.upvalues[slot as usize]
-                            .location
-                            .clone(),
-                    ));
-                }
-                OpCode::SetUpvalue => {
-                    let slot = self.read_u32();
-                    *self.frame().closure.upvalues[slot as usize]
-                        .location
-                        .borrow_mut() = self.peek(0).clone();
-                }
-                OpCode::Call => {
-                    let args_c = self.read_u32();
-                    if !self.call(args_c) {
-                        return InterpretResult::RuntimeError;
-                    }
-                }
-                OpCode::Debug => {
-                    self.debug();
+#[cfg(not(feature = "plugins"))]
+fn load_plugin(path: &str, _natives: &mut Vec<Native>) {
+    eprintln!(
+        "Could not load plugin '{}': rlox was built without the 'plugins' feature.",
+        path
+    );
+    std::process::exit(64);
+}
+
+#[cfg(feature = "http")]
+fn add_network(natives: &mut Vec<Native>) {
+    natives.extend(natives::network());
+}
+
+#[cfg(not(feature = "http"))]
+fn add_network(_natives: &mut Vec<Native>) {}
+
+fn explain(code: String) -> ! {
+    match ErrorCode::parse(&code) {
+        Some(code) => {
+            // a reader like `head` that stops early is no error
+            let mut out = std::io::stdout().lock();
+            match writeln!(out, "{}", code.explain()).and_then(|_| out.flush()) {
+                Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+                    eprintln!("Could not write the explanation: {}", e);
+                    std::process::exit(74);
                 }
+                _ => std::process::exit(0),
             }
         }
-    }
-
-    fn debug(&self) {
-        print!("stack: ");
-        for v in &self.stack {
-            print!("[{}] ", v.print());
+        None => {
+            eprintln!("Unknown error code '{}'.", code);
+            std::process::exit(64);
         }
-        println!();
     }
+}
 
-    fn capture_upvalue(&mut self, i: usize) -> UpValue {
-        if let Value::Lifted(lifted) = &self.stack[i] {
-            UpValue {
-                location: lifted.clone(),
+fn watch_files(f_names: Vec<String>, options: Options) -> ! {
+    let mut last_modified = vec![None; f_names.len()];
+    loop {
+        let mut changed = false;
+        for (f_name, last_modified) in f_names.iter().zip(last_modified.iter_mut()) {
+            // editors often replace the file on save, so it may briefly be missing
+            if let Ok(modified) = std::fs::metadata(f_name).and_then(|m| m.modified()) {
+                if *last_modified != Some(modified) {
+                    *last_modified = Some(modified);
+                    debug!("{} changed, running it again", f_name);
+                    changed = true;
+                }
             }
-        } else {
-            let lifted = Rc::new(RefCell::new(self.stack[i].clone()));
-            self.stack[i] = Value::Lifted(lifted.clone());
-            UpValue { location: lifted }
         }
+        if changed {
+            print!("\x1b[2J\x1b[H");
+            run_files(f_names.clone(), options.clone());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(250));
     }
+}
 
-    fn read_u32(&mut self) -> u32 {
-        let bytes = &self.frame().closure.function.chunk.code[self.frame().ip..self.frame().ip + 4];
-        let sized_bytes = bytes.try_into().unwrap();
-        self.frame_mut().ip += 4;
-        u32::from_be_bytes(sized_bytes)
+fn reporter(options: &Options) -> Rc<dyn Reporter> {
+    if options.json_errors {
+        Rc::new(JsonReporter)
+    } else {
+        Rc::new(HumanReporter {
+            style: options.style,
+        })
     }
+}
 
-    fn read_bool(&mut self) -> bool {
-        let code = self.frame().closure.function.chunk.code[self.frame().ip];
-        self.frame_mut().ip += 1;
-        code != 0
+fn sources_of(f_names: &[String]) -> Rc<SourceMap> {
+    let mut sources = SourceMap::new();
+    for f_name in f_names {
+        sources.add(f_name);
     }
+    Rc::new(sources)
+}
 
-    fn call(&mut self, argc: u32) -> bool {
-        let f = self.peek(argc as usize);
-        if f.is_closure() {
-            let function = f.as_function();
-            if function.arity != argc {
-                self.runtime_error(&format!(
-                    "Expected {} arguments but got {}.",
-                    function.arity, argc
-                ));
-                false
-            } else {
-                let closure = f.as_closure();
-                self.frames.push(CallStack {
-                    closure,
-                    ip: 0,
-                    offset: self.stack.len() - argc as usize,
+// Reads the files `f_names`, or reports the first that cannot be read.
+fn read_sources(f_names: &[String], options: &Options) -> Option<Vec<String>> {
+    let mut sources = vec![];
+    for f_name in f_names {
+        match std::fs::read_to_string(f_name) {
+            Ok(source) => sources.push(source),
+            Err(e) => {
+                reporter(options).report(&Diagnostic {
+                    code: ErrorCode::UnreadableFile,
+                    file: Some(f_name.clone()),
+                    line: 0,
+                    column: 0,
+                    message: format!("Could not read the file: {}.", e),
+                    at: None,
+                    frames: vec![],
                 });
-                true
+                return None;
             }
-        } else {
-            false
         }
     }
+    Some(sources)
+}
+
+// Like `read_sources`, exiting when a file cannot be read.
+fn read_sources_or_exit(f_names: &[String], options: &Options) -> Vec<String> {
+    read_sources(f_names, options).unwrap_or_else(|| std::process::exit(74))
+}
+
+// A parser compiling `sources`, read from the files `f_names`, into one
+// script.
+fn parser<'a>(f_names: &[String], sources: &'a [String], options: &Options) -> Parser<'a> {
+    let mut compiler = Parser::init(&sources[0]);
+    for source in &sources[1..] {
+        compiler.add_file(source);
+    }
+    compiler.sources = sources_of(f_names);
+    compiler.reporter = reporter(options);
+    compiler.natives = options.natives.clone();
+    compiler.warn_shadowing = options.warn_shadowing;
+    compiler.print_exprs = options.print_exprs;
+    compiler.shared_constants = options.shared_constants;
+    if options.prelude {
+        compiler.load_prelude();
+    }
+    compiler
+}
 
-    fn concatenate(&mut self) {
-        let b = self.pop();
-        let mut a = self.pop().as_str();
-        a.push_str(&b.as_str());
-        self.push(Value::string(&a));
+fn check_files(f_names: Vec<String>, options: Options) {
+    let sources = read_sources_or_exit(&f_names, &options);
+    let mut compiler = parser(&f_names, &sources, &options);
+    if compiler.compile().is_none() || compiler.had_warning {
+        std::process::exit(65);
     }
+}
 
-    fn peek(&self, depth: usize) -> &Value {
-        &self.stack[self.stack.len() - 1 - depth]
+fn minify_file(f_name: String, options: Options) {
+    let f_names = vec![f_name];
+    let sources = read_sources_or_exit(&f_names, &options);
+    match minifier::minify(parser(&f_names, &sources, &options), &sources[0]) {
+        Some(minified) => print!("{}", minified),
+        None => std::process::exit(65),
     }
+}
+
+fn disassemble_file(f_name: String, disassembly: DisassemblyOptions, options: Options) {
+    let f_names = vec![f_name];
+    let sources = read_sources_or_exit(&f_names, &options);
+    let script = match parser(&f_names, &sources, &options).compile() {
+        Some(script) => script,
+        None => std::process::exit(65),
+    };
+    let mut out = String::new();
+    script.write_disassembly(disassembly, &mut out).unwrap();
+    print!("{}", out);
+}
 
-    fn runtime_error(&mut self, msg: &str) {
-        eprintln!("{}", msg);
-        for frame in self.frames.iter().rev() {
-            let instruction = frame.ip - 1;
-            eprintln!(
-                "[line {}] in {}",
-                frame.closure.function.chunk.lines[instruction], frame.closure.function.name
-            );
+fn emit_asm_file(f_name: String, options: Options) {
+    let f_names = vec![f_name];
+    let sources = read_sources_or_exit(&f_names, &options);
+    let script = match parser(&f_names, &sources, &options).compile() {
+        Some(script) => script,
+        None => std::process::exit(65),
+    };
+    match asm::write(&script) {
+        Ok(out) => print!("{}", out),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(65);
         }
-        self.reset_stack();
     }
-
-    fn reset_stack(&mut self) {}
 }
 
-fn main() {
-    match args().count() {
-        2 => {
-            let mut args = args();
-            args.next();
-            run_file(args.next().unwrap());
+// Hand-written bytecode is not trusted: it always runs on a checked VM.
+fn assemble_file(f_name: String, mut options: Options) -> i32 {
+    let f_names = vec![f_name];
+    let sources = read_sources_or_exit(&f_names, &options);
+    match asm::parse(&sources[0], &options.natives) {
+        Ok(script) => {
+            options.config.checked = true;
+            run_script(script, &f_names, &options)
         }
-        _ => {
-            println!("Usage: rlox [script]");
-            std::process::exit(64);
+        Err(error) => {
+            eprintln!("{}:{}: {}", f_names[0], error.line, error.message);
+            65
         }
     }
 }
 
-fn run_file(f_name: String) {
-    let source = std::fs::read_to_string(f_name).unwrap();
-    let mut compiler = Parser::init(&source);
-    let script = compiler.compile();
+// Returns the exit status: 65 if the script does not compile and 70 if it
+// fails at runtime, as clox does, or 74 if a file cannot be read.
+fn run_files(f_names: Vec<String>, options: Options) -> i32 {
+    let sources = match read_sources(&f_names, &options) {
+        Some(sources) => sources,
+        None => return 74,
+    };
+    let mut compiler = parser(&f_names, &sources, &options);
+    match compiler.compile() {
+        Some(script) => run_script(script, &f_names, &options),
+        None => 65,
+    }
+}
 
-    if let Some(script) = script {
-        let mut vm = VM {
-            frames: vec![CallStack {
-                closure: Closure {
-                    function: Rc::new(script),
-                    upvalues: vec![],
-                },
-                offset: 0,
-                ip: 0,
-            }],
-            stack: vec![],
+fn run_script(script: Function, f_names: &[String], options: &Options) -> i32 {
+    let mut vm = VM::new(script, options.config.clone());
+    vm.reporter = reporter(options);
+    vm.sources = sources_of(f_names);
+    if options.stats {
+        vm.stats = Some(Stats::new());
+    }
+    if let Some(path) = options.record.as_ref().or(options.replay.as_ref()) {
+        let trace = if options.record.is_some() {
+            Trace::record(path)
+        } else {
+            Trace::replay(path)
         };
-        vm.run();
-    } else {
+        match trace {
+            Ok(trace) => vm.trace = Some(trace),
+            Err(msg) => {
+                eprintln!("Could not open trace '{}': {}", path, msg);
+                std::process::exit(74);
+            }
+        }
+    }
+    let result = interrupt::catch(|| vm.run());
+    if let Some(trace) = &mut vm.trace {
+        if let Err(msg) = trace.finish() {
+            eprintln!("Could not write trace: {}", msg);
+            std::process::exit(74);
+        }
+    }
+    if let Some(stats) = &vm.stats {
+        stats.print();
+    }
+    match result {
+        InterpretResult::Ok => 0,
+        _ => 70,
     }
 }
@@ -0,0 +1,85 @@
+use crate::compiler::Parser;
+use crate::compiler::Scanner;
+use crate::compiler::TokenType;
+use crate::compiler::KEYWORDS;
+use std::collections::HashMap;
+
+// Compiles the script to learn which declaration every identifier refers
+// to, then re-emits the tokens with the smallest whitespace possible. Each
+// binding receives its own short name, so shadowing and closures keep
+// resolving exactly as they did in the original source.
+pub fn minify(mut parser: Parser<'_>, source: &str) -> Option<String> {
+    parser.compile()?;
+
+    let mut names: HashMap<usize, String> = HashMap::new();
+    let mut renames: HashMap<usize, usize> = HashMap::new();
+    let mut next = 0;
+    // a name used as a named argument must keep matching its parameter,
+    // wherever that is declared
+    let kept = &parser.argument_names;
+    // and a new name must not hide a native
+    let mut reserved: Vec<&str> = parser.natives.iter().map(|n| n.name.as_str()).collect();
+    reserved.extend(kept);
+    for (usage, binding) in &parser.resolutions {
+        renames.insert(*usage, *binding);
+        if !names.contains_key(binding) {
+            let lexeme = Scanner::init(&source[*binding..]).scan_token().lexeme;
+            let name = if kept.contains(&lexeme) {
+                lexeme.to_string()
+            } else {
+                short_name(&mut next, &reserved)
+            };
+            names.insert(*binding, name);
+        }
+    }
+
+    let mut scanner = Scanner::init(source);
+    let mut out = String::new();
+    loop {
+        let token = scanner.scan_token();
+        if token.kind == TokenType::Eof {
+            break;
+        }
+        let text = match renames.get(&token.start) {
+            Some(binding) if token.kind == TokenType::Identifier => &names[binding],
+            _ => token.lexeme,
+        };
+        if needs_space(&out, text) {
+            out.push(' ');
+        }
+        out.push_str(text);
+    }
+    out.push('\n');
+    Some(out)
+}
+
+// The next short name that is neither a keyword nor `reserved`.
+fn short_name(next: &mut usize, reserved: &[&str]) -> String {
+    loop {
+        let mut name = String::new();
+        let mut i = *next;
+        *next += 1;
+        loop {
+            name.insert(0, (b'a' + (i % 26) as u8) as char);
+            if i < 26 {
+                break;
+            }
+            i = i / 26 - 1;
+        }
+        if !KEYWORDS.contains(&name.as_str()) && !reserved.contains(&name.as_str()) {
+            return name;
+        }
+    }
+}
+
+fn needs_space(before: &str, after: &str) -> bool {
+    let last = match before.chars().last() {
+        Some(c) => c,
+        None => return false,
+    };
+    let first = after.chars().next().unwrap();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    (is_word(last) && is_word(first))
+        || ("!=<>".contains(last) && first == '=')
+        || (last == '/' && first == '/')
+}
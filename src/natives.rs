@@ -0,0 +1,788 @@
+use crate::chunk::Capability;
+use crate::chunk::Native;
+use crate::chunk::Value;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Declares a native from a plain Rust function. The generated constructor
+// has the function's name and returns a `Native` whose arity matches the
+// parameter list and which checks each argument's type before calling the
+// body. The body may return any `IntoValue` type, or `Result<_, String>`
+// to report a runtime error.
+macro_rules! lox_native {
+    (@one $arg:ident) => {
+        1
+    };
+    (@wrap $(#[$meta:meta])* $name:ident ($($arg:ident: $ty:ty),*) $ret:ty, $body:block, $into:expr) => {
+        $(#[$meta])*
+        pub fn $name() -> Native {
+            fn body($($arg: $ty),*) -> $ret $body
+            fn call(args: &[Value]) -> Result<Value, String> {
+                #[allow(unused_mut, unused_variables)]
+                let mut args = args.iter();
+                $(
+                    let value = args.next().unwrap();
+                    let $arg = <$ty as FromValue>::from_value(value).ok_or_else(|| {
+                        format!(
+                            "Argument '{}' of {}() must be {}.",
+                            stringify!($arg),
+                            stringify!($name),
+                            <$ty as FromValue>::KIND
+                        )
+                    })?;
+                )*
+                $into(body($($arg),*))
+            }
+            Native::new(stringify!($name), 0 $(+ lox_native!(@one $arg))*, call)
+        }
+    };
+    ($(#[$meta:meta])* fn $name:ident($($arg:ident: $ty:ty),*) -> Result<$ret:ty, String> $body:block) => {
+        lox_native!(@wrap $(#[$meta])* $name ($($arg: $ty),*) Result<$ret, String>, $body,
+            |r: Result<$ret, String>| r.map(IntoValue::into_value));
+    };
+    ($(#[$meta:meta])* fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty $body:block) => {
+        lox_native!(@wrap $(#[$meta])* $name ($($arg: $ty),*) $ret, $body,
+            |r: $ret| Ok(IntoValue::into_value(r)));
+    };
+}
+
+pub trait FromValue: Sized {
+    const KIND: &'static str;
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl FromValue for f64 {
+    const KIND: &'static str = "a number";
+    fn from_value(value: &Value) -> Option<Self> {
+        if value.is_number() {
+            Some(value.as_number())
+        } else {
+            None
+        }
+    }
+}
+
+impl FromValue for i64 {
+    const KIND: &'static str = "an integer";
+    fn from_value(value: &Value) -> Option<Self> {
+        if value.is_int() {
+            Some(value.as_int())
+        } else {
+            None
+        }
+    }
+}
+
+impl FromValue for bool {
+    const KIND: &'static str = "a bool";
+    fn from_value(value: &Value) -> Option<Self> {
+        if value.is_bool() {
+            Some(value.as_bool())
+        } else {
+            None
+        }
+    }
+}
+
+impl FromValue for String {
+    const KIND: &'static str = "a string";
+    fn from_value(value: &Value) -> Option<Self> {
+        if value.is_string() {
+            Some(value.as_str())
+        } else {
+            None
+        }
+    }
+}
+
+impl FromValue for Vec<u8> {
+    const KIND: &'static str = "bytes";
+    fn from_value(value: &Value) -> Option<Self> {
+        if value.is_bytes() {
+            Some(value.as_bytes())
+        } else {
+            None
+        }
+    }
+}
+
+impl FromValue for Vec<Value> {
+    const KIND: &'static str = "a tuple";
+    fn from_value(value: &Value) -> Option<Self> {
+        if value.is_tuple() {
+            Some(value.as_tuple())
+        } else {
+            None
+        }
+    }
+}
+
+impl FromValue for Value {
+    const KIND: &'static str = "a value";
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(value.clone())
+    }
+}
+
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::from_number(self)
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Value {
+        Value::from_int(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::from_bool(self)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::string(&self)
+    }
+}
+
+impl IntoValue for Vec<u8> {
+    fn into_value(self) -> Value {
+        Value::bytes(self)
+    }
+}
+
+impl IntoValue for Vec<Value> {
+    fn into_value(self) -> Value {
+        Value::tuple(self)
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::nil()
+    }
+}
+
+pub fn standard() -> Vec<Native> {
+    #[allow(unused_mut)]
+    let mut natives = vec![
+        clock().needing(Capability::Time),
+        now().needing(Capability::Time),
+        formatTime(),
+        parseTime(),
+        substr(),
+        len(),
+        str(),
+        doc(),
+        range(),
+        reverse(),
+        div(),
+        bytes(),
+        slice(),
+        encodeUtf8(),
+        decodeUtf8(),
+        stringBuilder(),
+        append(),
+        toString(),
+        format(),
+        isNan(),
+        isFinite(),
+    ];
+    #[cfg(feature = "regex")]
+    natives.extend(vec![reMatch(), reFindAll(), reReplace()]);
+    natives
+}
+
+// The natives the prelude calls, which `Parser::load_prelude` adds to
+// those given to the parser when they are missing.
+pub fn prelude() -> Vec<Native> {
+    vec![len(), slice(), div()]
+}
+
+// Replaces clock() and now() with a logical clock that starts at the
+// epoch and advances by one millisecond per call, so that a script reading
+// the time prints the same output on every run.
+pub fn make_deterministic(natives: &mut [Native]) {
+    let ticks = Rc::new(Cell::new(0u64));
+    let tick = move || {
+        ticks.set(ticks.get() + 1);
+        ticks.get() as f64
+    };
+    for native in natives.iter_mut() {
+        let tick = tick.clone();
+        match native.name.as_str() {
+            "clock" => native.function = Rc::new(move |_| Ok(Value::from_number(tick() / 1000.0))),
+            "now" => native.function = Rc::new(move |_| Ok(Value::from_number(tick()))),
+            _ => {}
+        }
+    }
+}
+
+lox_native! {
+    fn clock() -> Result<f64, String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?;
+        Ok(now.as_secs_f64())
+    }
+}
+
+lox_native! {
+    fn now() -> Result<f64, String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?;
+        Ok(now.as_millis() as f64)
+    }
+}
+
+// formatTime() and parseTime() work on epoch milliseconds in UTC, with
+// the fields %Y (year), %m, %d, %H, %M, %S (two digits each), %L
+// (three-digit milliseconds) and %% for a literal '%'.
+lox_native! {
+    #[allow(non_snake_case)]
+    fn formatTime(ms: f64, fmt: String) -> Result<String, String> {
+        if !ms.is_finite() {
+            return Err("formatTime() expects a finite time.".to_string());
+        }
+        let ms = ms.floor() as i64;
+        let days = ms.div_euclid(86_400_000);
+        let of_day = ms.rem_euclid(86_400_000);
+        let (year, month, day) = civil_from_days(days);
+        let mut out = String::new();
+        let mut chars = fmt.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('Y') => out.push_str(&format!("{:04}", year)),
+                Some('m') => out.push_str(&format!("{:02}", month)),
+                Some('d') => out.push_str(&format!("{:02}", day)),
+                Some('H') => out.push_str(&format!("{:02}", of_day / 3_600_000)),
+                Some('M') => out.push_str(&format!("{:02}", of_day / 60_000 % 60)),
+                Some('S') => out.push_str(&format!("{:02}", of_day / 1000 % 60)),
+                Some('L') => out.push_str(&format!("{:03}", of_day % 1000)),
+                Some('%') => out.push('%'),
+                other => return Err(unknown_time_field(other)),
+            }
+        }
+        Ok(out)
+    }
+}
+
+lox_native! {
+    #[allow(non_snake_case)]
+    fn parseTime(s: String, fmt: String) -> Result<f64, String> {
+        let mismatch = || format!("'{}' does not match the time format '{}'.", s, fmt);
+        let (mut year, mut month, mut day) = (1970, 1, 1);
+        let (mut hour, mut minute, mut second, mut milli) = (0, 0, 0, 0);
+        let mut input = s.as_str();
+        let mut chars = fmt.chars();
+        while let Some(c) = chars.next() {
+            let (field, digits) = match c {
+                '%' => match chars.next() {
+                    Some('Y') => (&mut year, 4),
+                    Some('m') => (&mut month, 2),
+                    Some('d') => (&mut day, 2),
+                    Some('H') => (&mut hour, 2),
+                    Some('M') => (&mut minute, 2),
+                    Some('S') => (&mut second, 2),
+                    Some('L') => (&mut milli, 3),
+                    Some('%') => {
+                        input = input.strip_prefix('%').ok_or_else(mismatch)?;
+                        continue;
+                    }
+                    other => return Err(unknown_time_field(other)),
+                },
+                c => {
+                    input = input.strip_prefix(c).ok_or_else(mismatch)?;
+                    continue;
+                }
+            };
+            let number = input.get(..digits).ok_or_else(mismatch)?;
+            if !number.chars().all(|c| c.is_ascii_digit()) {
+                return Err(mismatch());
+            }
+            *field = number.parse().map_err(|_| mismatch())?;
+            input = &input[digits..];
+        }
+        if !input.is_empty()
+            || !(1..=12).contains(&month)
+            || !(1..=days_in_month(year, month)).contains(&day)
+            || hour > 23
+            || minute > 59
+            || second > 59
+        {
+            return Err(mismatch());
+        }
+        let days = days_from_civil(year, month, day);
+        let ms = ((days * 24 + hour) * 60 + minute) * 60_000 + second * 1000 + milli;
+        Ok(ms as f64)
+    }
+}
+
+fn unknown_time_field(field: Option<char>) -> String {
+    match field {
+        Some(c) => format!("Unknown time format field '%{}'.", c),
+        None => "Time format ends with a lone '%'.".to_string(),
+    }
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar, and back
+// (http://howardhinnant.github.io/date_algorithms.html).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+lox_native! {
+    fn substr(s: String, start: f64, len: f64) -> Result<String, String> {
+        if start < 0.0 || len < 0.0 || start.fract() != 0.0 || len.fract() != 0.0 {
+            return Err("substr() expects a non-negative integer start and length.".to_string());
+        }
+        Ok(s.chars().skip(start as usize).take(len as usize).collect())
+    }
+}
+
+lox_native! {
+    fn len(s: Value) -> Result<i64, String> {
+        if s.is_string() {
+            Ok(s.as_str().chars().count() as i64)
+        } else if s.is_bytes() {
+            Ok(s.as_bytes().len() as i64)
+        } else if s.is_tuple() {
+            Ok(s.as_tuple().len() as i64)
+        } else {
+            Err("Argument 's' of len() must be a string, bytes or a tuple.".to_string())
+        }
+    }
+}
+
+// str(v) is v as print shows it.
+lox_native! {
+    fn str(v: Value) -> String {
+        v.print()
+    }
+}
+
+// doc(f) is the doc comment of the function f, or nil if it has none.
+lox_native! {
+    fn doc(f: Value) -> Result<Value, String> {
+        if f.is_closure() {
+            Ok(match &f.as_function().doc {
+                Some(doc) => Value::string(doc),
+                None => Value::Nil,
+            })
+        } else if f.is_native() {
+            Ok(Value::Nil)
+        } else {
+            Err("Argument 'f' of doc() must be a function.".to_string())
+        }
+    }
+}
+
+// range(start, end, step) is the tuple of the integers from start up to,
+// but not including, end, counting by step. A negative step counts down.
+lox_native! {
+    fn range(start: i64, end: i64, step: i64) -> Result<Vec<Value>, String> {
+        if step == 0 {
+            return Err("range() step must not be zero.".to_string());
+        }
+        let span = (end as i128 - start as i128) * step.signum() as i128;
+        let count = (span.max(0) + step.unsigned_abs() as i128 - 1) / step.unsigned_abs() as i128;
+        if count > MAX_LENGTH as i128 {
+            return Err(format!("range() must give at most {} integers.", MAX_LENGTH));
+        }
+        let mut values = Vec::with_capacity(count as usize);
+        let mut i = start;
+        while (step > 0 && i < end) || (step < 0 && i > end) {
+            values.push(Value::from_int(i));
+            i = match i.checked_add(step) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        Ok(values)
+    }
+}
+
+lox_native! {
+    fn reverse(v: Value) -> Result<Value, String> {
+        if v.is_string() {
+            Ok(Value::string(&v.as_str().chars().rev().collect::<String>()))
+        } else if v.is_bytes() {
+            Ok(Value::bytes(v.as_bytes().into_iter().rev().collect()))
+        } else if v.is_tuple() {
+            Ok(Value::tuple(v.as_tuple().into_iter().rev().collect()))
+        } else {
+            Err("Argument 'v' of reverse() must be a string, bytes or a tuple.".to_string())
+        }
+    }
+}
+
+// The most elements a native builds from a length the script gives, so
+// that a huge length is a runtime error instead of exhausting the host's
+// memory.
+pub const MAX_LENGTH: usize = 1 << 24;
+
+// bytes(len) is `len` zero bytes. Bytes are immutable like strings.
+lox_native! {
+    fn bytes(len: i64) -> Result<Vec<u8>, String> {
+        if len < 0 {
+            return Err("bytes() length must not be negative.".to_string());
+        }
+        if len as u64 > MAX_LENGTH as u64 {
+            return Err(format!("bytes() length must be at most {}.", MAX_LENGTH));
+        }
+        Ok(vec![0; len as usize])
+    }
+}
+
+// slice(b, start, end) is the bytes, or the tuple elements, of b from
+// start up to, but not including, end.
+lox_native! {
+    fn slice(b: Value, start: i64, end: i64) -> Result<Value, String> {
+        let len = if b.is_bytes() {
+            b.as_bytes().len()
+        } else if b.is_tuple() {
+            b.as_tuple().len()
+        } else {
+            return Err("Argument 'b' of slice() must be bytes or a tuple.".to_string());
+        };
+        if start < 0 || end < start || end as usize > len {
+            return Err(format!(
+                "slice() range {}..{} out of range for length {}.",
+                start, end, len
+            ));
+        }
+        let range = start as usize..end as usize;
+        if b.is_bytes() {
+            Ok(Value::bytes(b.as_bytes()[range].to_vec()))
+        } else {
+            Ok(Value::tuple(b.as_tuple()[range].to_vec()))
+        }
+    }
+}
+
+// stringBuilder() is an empty buffer that append() adds to in place, where
+// repeated '+' would copy the whole string every time.
+lox_native! {
+    #[allow(non_snake_case)]
+    fn stringBuilder() -> Value {
+        Value::string_builder(String::new())
+    }
+}
+
+// append(builder, v) adds v, as print shows it, to the end of builder and
+// returns builder.
+lox_native! {
+    fn append(builder: Value, v: Value) -> Result<Value, String> {
+        if !builder.is_string_builder() {
+            return Err("Argument 'builder' of append() must be a string builder.".to_string());
+        }
+        builder.as_string_builder().0.borrow_mut().push_str(&v.print());
+        Ok(builder)
+    }
+}
+
+lox_native! {
+    #[allow(non_snake_case)]
+    fn toString(builder: Value) -> Result<String, String> {
+        if !builder.is_string_builder() {
+            return Err("Argument 'builder' of toString() must be a string builder.".to_string());
+        }
+        let s = builder.as_string_builder().0.borrow().clone();
+        Ok(s)
+    }
+}
+
+lox_native! {
+    #[allow(non_snake_case)]
+    fn encodeUtf8(s: String) -> Vec<u8> {
+        s.into_bytes()
+    }
+}
+
+lox_native! {
+    #[allow(non_snake_case)]
+    fn decodeUtf8(b: Vec<u8>) -> Result<String, String> {
+        String::from_utf8(b).map_err(|e| format!("decodeUtf8() got invalid UTF-8: {}.", e))
+    }
+}
+
+// Integer division, rounding towards negative infinity. Lox has no '//'
+// operator for it since '//' starts a comment.
+lox_native! {
+    fn div(a: i64, b: i64) -> Result<i64, String> {
+        if b == 0 {
+            return Err("div() by zero.".to_string());
+        }
+        let q = a
+            .checked_div(b)
+            .ok_or_else(|| "div() overflows.".to_string())?;
+        if a % b != 0 && (a < 0) != (b < 0) {
+            Ok(q - 1)
+        } else {
+            Ok(q)
+        }
+    }
+}
+
+// format("x={}, y={:.2}", x, y) replaces each '{}' with the next argument
+// as print would show it. A placeholder may carry a minimum width and,
+// for numbers, a precision: '{:8}', '{:.2}', '{:8.2}', each at most
+// 65535. Numbers are right-aligned in their width, everything else
+// left-aligned. '{{' and '}}' stand for literal braces.
+pub fn format() -> Native {
+    Native::variadic("format", 1, |args| {
+        let template = String::from_value(&args[0])
+            .ok_or_else(|| "Argument 'template' of format() must be a string.".to_string())?;
+        let mut values = args[1..].iter();
+        let mut out = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    out.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    out.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| "Unclosed '{' in format() template.".to_string())?;
+                    let spec = &rest[..end];
+                    chars = rest[end + 1..].chars();
+                    let value = values
+                        .next()
+                        .ok_or_else(|| "Too few arguments for format() template.".to_string())?;
+                    out.push_str(&format_value(value, spec)?);
+                }
+                '}' => return Err("Unmatched '}' in format() template.".to_string()),
+                c => out.push(c),
+            }
+        }
+        if values.next().is_some() {
+            return Err("Too many arguments for format() template.".to_string());
+        }
+        Ok(Value::string(&out))
+    })
+}
+
+fn format_value(value: &Value, spec: &str) -> Result<String, String> {
+    let invalid = || format!("Invalid format() placeholder '{{{}}}'.", spec);
+    let spec = match spec.strip_prefix(':') {
+        Some(spec) => spec,
+        None if spec.is_empty() => spec,
+        None => return Err(invalid()),
+    };
+    let (width, precision) = match spec.find('.') {
+        Some(dot) => (&spec[..dot], Some(&spec[dot + 1..])),
+        None => (spec, None),
+    };
+    let width = if width.is_empty() {
+        0
+    } else {
+        format_size(width, spec).ok_or_else(invalid)??
+    };
+    match precision {
+        Some(precision) => {
+            let precision = format_size(precision, spec).ok_or_else(invalid)??;
+            let n = f64::from_value(value)
+                .ok_or_else(|| format!("Precision in '{{:{}}}' needs a number.", spec))?;
+            Ok(format!(
+                "{:>width$.precision$}",
+                n,
+                width = width,
+                precision = precision
+            ))
+        }
+        None if value.is_number() => Ok(format!("{:>width$}", value.print(), width = width)),
+        None => Ok(format!("{:<width$}", value.print(), width = width)),
+    }
+}
+
+// Parses a width or precision, which may be at most MAX_FORMAT_SIZE. Gives
+// None when `digits` is not a number.
+fn format_size(digits: &str, spec: &str) -> Option<Result<usize, String>> {
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(match digits.parse::<usize>() {
+        Ok(size) if size <= MAX_FORMAT_SIZE => Ok(size),
+        _ => Err(format!(
+            "Width and precision in '{{:{}}}' must be at most {}.",
+            spec, MAX_FORMAT_SIZE
+        )),
+    })
+}
+
+// Larger widths or precisions make the host's formatter panic.
+const MAX_FORMAT_SIZE: usize = u16::MAX as usize;
+
+// Arithmetic follows IEEE 754: 0/0 is NaN and 1/0 is infinite. NaN is not
+// equal to anything, itself included, and every ordering comparison with
+// it is false; -0 == 0. These let scripts check for such results.
+lox_native! {
+    #[allow(non_snake_case)]
+    fn isNan(v: Value) -> bool {
+        v.is_number() && v.as_number().is_nan()
+    }
+}
+
+lox_native! {
+    #[allow(non_snake_case)]
+    fn isFinite(v: Value) -> bool {
+        v.is_number() && v.as_number().is_finite()
+    }
+}
+
+// Natives that read the file system. Like network(), they are not part of
+// the standard set, and need the 'fs' permission to run.
+pub fn filesystem() -> Vec<Native> {
+    vec![readFileBytes().needing(Capability::Fs)]
+}
+
+lox_native! {
+    #[allow(non_snake_case)]
+    fn readFileBytes(path: String) -> Result<Vec<u8>, String> {
+        std::fs::read(&path).map_err(|e| format!("Could not read '{}': {}.", path, e))
+    }
+}
+
+// Natives that reach the network. They are not part of the standard set,
+// and need the 'net' permission to run.
+#[cfg(feature = "http")]
+pub fn network() -> Vec<Native> {
+    vec![
+        httpGet().needing(Capability::Net),
+        httpPost().needing(Capability::Net),
+    ]
+}
+
+// Returns the response as a (status, body) tuple. Error statuses are
+// returned like any other; only failing to get a response is an error.
+#[cfg(feature = "http")]
+fn http_response(response: Result<ureq::Response, ureq::Error>) -> Result<Value, String> {
+    let response = match response {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => return Err(e.to_string()),
+    };
+    let status = Value::from_int(response.status() as i64);
+    let body = response.into_string().map_err(|e| e.to_string())?;
+    Ok(Value::tuple(vec![status, Value::string(&body)]))
+}
+
+lox_native! {
+    #[cfg(feature = "http")]
+    #[allow(non_snake_case)]
+    fn httpGet(url: String) -> Result<Value, String> {
+        http_response(ureq::get(&url).call())
+    }
+}
+
+lox_native! {
+    #[cfg(feature = "http")]
+    #[allow(non_snake_case)]
+    fn httpPost(url: String, body: String) -> Result<Value, String> {
+        http_response(ureq::post(&url).send_string(&body))
+    }
+}
+
+// Regular expressions use the syntax of the regex crate. Matches are
+// returned as tuples of strings since the language has no list type.
+#[cfg(feature = "regex")]
+fn regex(pattern: &str) -> Result<regex::Regex, String> {
+    regex::Regex::new(pattern).map_err(|e| format!("Invalid regular expression: {}", e))
+}
+
+// The first match as a tuple of the whole match and each capture group
+// (nil for groups that did not take part), or nil without a match.
+lox_native! {
+    #[cfg(feature = "regex")]
+    #[allow(non_snake_case)]
+    fn reMatch(pattern: String, s: String) -> Result<Value, String> {
+        let captures = match regex(&pattern)?.captures(&s) {
+            Some(captures) => captures,
+            None => return Ok(Value::nil()),
+        };
+        let groups = captures
+            .iter()
+            .map(|group| group.map_or(Value::nil(), |m| Value::string(m.as_str())))
+            .collect();
+        Ok(Value::tuple(groups))
+    }
+}
+
+lox_native! {
+    #[cfg(feature = "regex")]
+    #[allow(non_snake_case)]
+    fn reFindAll(pattern: String, s: String) -> Result<Value, String> {
+        let matches = regex(&pattern)?
+            .find_iter(&s)
+            .map(|m| Value::string(m.as_str()))
+            .collect();
+        Ok(Value::tuple(matches))
+    }
+}
+
+// Replaces every match; the replacement may refer to groups as $1 or ${name}.
+lox_native! {
+    #[cfg(feature = "regex")]
+    #[allow(non_snake_case)]
+    fn reReplace(pattern: String, s: String, replacement: String) -> Result<String, String> {
+        Ok(regex(&pattern)?
+            .replace_all(&s, replacement.as_str())
+            .into_owned())
+    }
+}
@@ -0,0 +1,89 @@
+use crate::chunk::Native;
+use crate::chunk::Value;
+use log::debug;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::os::raw::c_void;
+use std::rc::Rc;
+
+// A plugin is a shared library exporting
+//
+//     void rlox_plugin_init(void *registry, rlox_register_fn register);
+//
+// which calls `register(registry, name, arity, function)` once per native.
+// Plugin functions work on numbers only: they receive their arguments as
+// an array of doubles and return a double, so they can be written in any
+// language with a C ABI.
+pub type PluginFn = extern "C" fn(args: *const f64, argc: u32) -> f64;
+type RegisterFn =
+    extern "C" fn(registry: *mut c_void, name: *const c_char, arity: u32, function: PluginFn);
+type InitFn = extern "C" fn(registry: *mut c_void, register: RegisterFn);
+
+const RTLD_NOW: c_int = 2;
+
+#[link(name = "dl")]
+extern "C" {
+    fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlerror() -> *mut c_char;
+}
+
+pub fn load(path: &str, natives: &mut Vec<Native>) -> Result<(), String> {
+    let path = CString::new(path).map_err(|e| e.to_string())?;
+    let symbol = CString::new("rlox_plugin_init").unwrap();
+    // the library is never closed: its functions live as long as the VM
+    unsafe {
+        let handle = dlopen(path.as_ptr(), RTLD_NOW);
+        if handle.is_null() {
+            return Err(last_error());
+        }
+        let init = dlsym(handle, symbol.as_ptr());
+        if init.is_null() {
+            return Err(last_error());
+        }
+        let init: InitFn = std::mem::transmute(init);
+        let before = natives.len();
+        init(natives as *mut Vec<Native> as *mut c_void, register);
+        debug!(
+            "loaded plugin {:?} with {} natives",
+            path,
+            natives.len() - before
+        );
+    }
+    Ok(())
+}
+
+extern "C" fn register(registry: *mut c_void, name: *const c_char, arity: u32, function: PluginFn) {
+    let natives = unsafe { &mut *(registry as *mut Vec<Native>) };
+    let name = unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned();
+    let message = format!("Plugin function '{}' only accepts numbers.", name);
+    natives.push(Native {
+        name,
+        arity,
+        variadic: false,
+        capability: None,
+        function: Rc::new(move |args: &[Value]| {
+            if !args.iter().all(|arg| arg.is_number()) {
+                return Err(message.clone());
+            }
+            let args: Vec<f64> = args.iter().map(|arg| arg.as_number()).collect();
+            Ok(Value::from_number(function(
+                args.as_ptr(),
+                args.len() as u32,
+            )))
+        }),
+    });
+}
+
+unsafe fn last_error() -> String {
+    let error = dlerror();
+    if error.is_null() {
+        "unknown error".to_string()
+    } else {
+        CStr::from_ptr(error).to_string_lossy().into_owned()
+    }
+}
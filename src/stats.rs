@@ -0,0 +1,70 @@
+use crate::chunk::OpCode;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+#[derive(Debug)]
+pub struct Stats {
+    opcodes: [u64; 256],
+    functions: HashMap<String, u64>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            opcodes: [0; 256],
+            functions: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, instruction: u8, function: &str) {
+        self.opcodes[instruction as usize] += 1;
+        if let Some(count) = self.functions.get_mut(function) {
+            *count += 1;
+        } else {
+            self.functions.insert(function.to_string(), 1);
+        }
+    }
+
+    pub fn print(&self) {
+        let total: u64 = self.opcodes.iter().sum();
+        let mut opcodes: Vec<(String, u64)> = self
+            .opcodes
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .filter_map(|(op, count)| {
+                Some((format!("{:?}", OpCode::try_from(op as u8).ok()?), *count))
+            })
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut functions: Vec<(String, u64)> = self
+            .functions
+            .iter()
+            .map(|(name, count)| (name.clone(), *count))
+            .collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        eprintln!("== stats ==");
+        eprintln!("{:<16} {:>12} {:>7}", "opcode", "count", "%");
+        for (name, count) in opcodes {
+            eprintln!("{:<16} {:>12} {:>6.2}%", name, count, percent(count, total));
+        }
+        eprintln!();
+        eprintln!("{:<16} {:>12} {:>7}", "function", "count", "%");
+        for (name, count) in functions {
+            eprintln!("{:<16} {:>12} {:>6.2}%", name, count, percent(count, total));
+        }
+        eprintln!();
+        eprintln!("{:<16} {:>12}", "total", total);
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    count as f64 * 100.0 / total as f64
+}
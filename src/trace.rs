@@ -0,0 +1,309 @@
+use crate::chunk::Object;
+use crate::chunk::Symbol;
+use crate::chunk::Value;
+use num_bigint::BigInt;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::rc::Rc;
+
+const MAGIC: &[u8; 8] = b"RLOXTRC1";
+
+const INSTRUCTION: u8 = 0;
+const CALL: u8 = 1;
+const NATIVE_OK: u8 = 2;
+const NATIVE_ERR: u8 = 3;
+
+const NIL: u8 = 0;
+const BOOL: u8 = 1;
+const NUMBER: u8 = 2;
+const STRING: u8 = 3;
+const INT: u8 = 4;
+const BIG_INT: u8 = 5;
+// a count, then each element
+const TUPLE: u8 = 6;
+// a count, then the name and value of each field
+const RECORD: u8 = 7;
+// a length, then the bytes
+const BYTES: u8 = 8;
+// the text of a new string builder
+const STRING_BUILDER: u8 = 9;
+// the index of the argument holding a string builder, then its text
+const ARGUMENT_BUILDER: u8 = 10;
+
+// An execution trace is a flat sequence of events, one per executed
+// instruction and per call, plus the result of every native call. Replaying
+// checks that execution follows the recorded events exactly and feeds the
+// recorded native results back instead of calling the natives, so clock()
+// or any plugin returns the same values as in the recorded run.
+pub enum Trace {
+    Record(BufWriter<File>),
+    Replay { bytes: Vec<u8>, position: usize },
+}
+
+impl Trace {
+    pub fn record(path: &str) -> Result<Self, String> {
+        let mut file = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+        file.write_all(MAGIC).map_err(|e| e.to_string())?;
+        Ok(Trace::Record(file))
+    }
+
+    pub fn replay(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        if !bytes.starts_with(MAGIC) {
+            return Err("not an rlox trace file".to_string());
+        }
+        Ok(Trace::Replay {
+            bytes,
+            position: MAGIC.len(),
+        })
+    }
+
+    pub fn finish(&mut self) -> Result<(), String> {
+        match self {
+            Trace::Record(file) => file.flush().map_err(|e| e.to_string()),
+            Trace::Replay { .. } => Ok(()),
+        }
+    }
+
+    pub fn instruction(&mut self, depth: u32, ip: u32, op: u8) -> Result<(), String> {
+        let mut event = vec![INSTRUCTION];
+        event.extend_from_slice(&depth.to_be_bytes());
+        event.extend_from_slice(&ip.to_be_bytes());
+        event.push(op);
+        self.event(&event, || {
+            format!("instruction {} at offset {} in frame {}", op, ip, depth)
+        })
+    }
+
+    pub fn call(&mut self, name: &str, argc: u32) -> Result<(), String> {
+        let mut event = vec![CALL];
+        write_str(&mut event, name);
+        event.extend_from_slice(&argc.to_be_bytes());
+        self.event(&event, || {
+            format!("call to {} with {} arguments", name, argc)
+        })
+    }
+
+    // Calls the native through `call`, or replays its result. `args` are
+    // the arguments of the call, which a string builder result may be one of.
+    pub fn native<F>(&mut self, args: &[Value], call: F) -> Result<Result<Value, String>, String>
+    where
+        F: FnOnce() -> Result<Value, String>,
+    {
+        match self {
+            Trace::Record(file) => {
+                let result = call();
+                let mut event = vec![];
+                match &result {
+                    Ok(v) => {
+                        event.push(NATIVE_OK);
+                        write_value(&mut event, v, args)?;
+                    }
+                    Err(msg) => {
+                        event.push(NATIVE_ERR);
+                        write_str(&mut event, msg);
+                    }
+                }
+                file.write_all(&event).map_err(|e| e.to_string())?;
+                Ok(result)
+            }
+            Trace::Replay { bytes, position } => {
+                let mut reader = Reader {
+                    bytes,
+                    position,
+                    args,
+                };
+                match reader.u8()? {
+                    NATIVE_OK => Ok(Ok(reader.value()?)),
+                    NATIVE_ERR => Ok(Err(reader.str()?)),
+                    _ => Err("replay diverged: expected a native call result".to_string()),
+                }
+            }
+        }
+    }
+
+    fn event<F>(&mut self, event: &[u8], describe: F) -> Result<(), String>
+    where
+        F: FnOnce() -> String,
+    {
+        match self {
+            Trace::Record(file) => file.write_all(event).map_err(|e| e.to_string()),
+            Trace::Replay { bytes, position } => {
+                let end = *position + event.len();
+                if bytes.get(*position..end) == Some(event) {
+                    *position = end;
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "replay diverged at byte {}: did not expect {}",
+                        position,
+                        describe()
+                    ))
+                }
+            }
+        }
+    }
+}
+
+fn write_str(event: &mut Vec<u8>, s: &str) {
+    event.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    event.extend_from_slice(s.as_bytes());
+}
+
+fn write_value(event: &mut Vec<u8>, v: &Value, args: &[Value]) -> Result<(), String> {
+    if let Value::Lifted(l) = v {
+        return write_value(event, &l.borrow(), args);
+    }
+    if v.is_string() {
+        event.push(STRING);
+        write_str(event, &v.as_str());
+        return Ok(());
+    }
+    match v {
+        Value::Nil => event.push(NIL),
+        Value::Bool(b) => {
+            event.push(BOOL);
+            event.push(*b as u8);
+        }
+        Value::Number(n) => {
+            event.push(NUMBER);
+            event.extend_from_slice(&n.to_be_bytes());
+        }
+        Value::Int(i) => {
+            event.push(INT);
+            event.extend_from_slice(&i.to_be_bytes());
+        }
+        Value::Obj(o) => match &**o {
+            Object::BigInt(i) => {
+                event.push(BIG_INT);
+                write_str(event, &i.to_string());
+            }
+            Object::Tuple(values) => {
+                event.push(TUPLE);
+                event.extend_from_slice(&(values.len() as u32).to_be_bytes());
+                for value in values {
+                    write_value(event, value, args)?;
+                }
+            }
+            Object::Bytes(bytes) => {
+                event.push(BYTES);
+                event.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+                event.extend_from_slice(bytes);
+            }
+            // a builder the native was given, like the one append() returns,
+            // is refilled in place on replay, so that every copy sees the text
+            Object::StringBuilder(builder) => {
+                let argument = args.iter().position(|arg| {
+                    arg.is_string_builder() && Rc::ptr_eq(&arg.as_string_builder().0, &builder.0)
+                });
+                match argument {
+                    Some(i) => {
+                        event.push(ARGUMENT_BUILDER);
+                        event.extend_from_slice(&(i as u32).to_be_bytes());
+                    }
+                    None => event.push(STRING_BUILDER),
+                }
+                write_str(event, &builder.0.borrow());
+            }
+            Object::Record(fields) => {
+                event.push(RECORD);
+                event.extend_from_slice(&(fields.len() as u32).to_be_bytes());
+                for (name, value) in fields {
+                    write_str(event, &name.name());
+                    write_value(event, value, args)?;
+                }
+            }
+            _ => return Err(format!("cannot record native result {}", v.print())),
+        },
+        other => return Err(format!("cannot record native result {}", other.print())),
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: &'a mut usize,
+    args: &'a [Value],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let start = *self.position;
+        let bytes = self
+            .bytes
+            .get(start..start + n)
+            .ok_or_else(|| "replay diverged: trace ended early".to_string())?;
+        *self.position += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.u8()? {
+            NIL => Ok(Value::nil()),
+            BOOL => Ok(Value::from_bool(self.u8()? != 0)),
+            NUMBER => Ok(Value::from_number(f64::from_be_bytes(
+                self.take(8)?.try_into().unwrap(),
+            ))),
+            STRING => Ok(Value::string(&self.str()?)),
+            INT => Ok(Value::from_int(i64::from_be_bytes(
+                self.take(8)?.try_into().unwrap(),
+            ))),
+            BIG_INT => {
+                let text = self.str()?;
+                let i: BigInt = text
+                    .parse()
+                    .map_err(|_| format!("corrupt trace: bad integer {}", text))?;
+                Ok(Value::big_int(i))
+            }
+            TUPLE => {
+                let count = self.u32()?;
+                let values = (0..count).map(|_| self.value()).collect::<Result<_, _>>()?;
+                Ok(Value::tuple(values))
+            }
+            BYTES => {
+                let len = self.u32()? as usize;
+                Ok(Value::bytes(self.take(len)?.to_vec()))
+            }
+            STRING_BUILDER => Ok(Value::string_builder(self.str()?)),
+            ARGUMENT_BUILDER => {
+                let index = self.u32()? as usize;
+                let text = self.str()?;
+                let arg = self
+                    .args
+                    .get(index)
+                    .filter(|arg| arg.is_string_builder())
+                    .ok_or_else(|| {
+                        format!("replay diverged: argument {} is no string builder", index)
+                    })?;
+                *arg.as_string_builder().0.borrow_mut() = text;
+                Ok(arg.clone())
+            }
+            RECORD => {
+                let count = self.u32()?;
+                let mut fields = vec![];
+                for _ in 0..count {
+                    let name = Symbol::intern(&self.str()?);
+                    fields.push((name, self.value()?));
+                }
+                Ok(Value::record(fields))
+            }
+            tag => Err(format!("corrupt trace: unknown value tag {}", tag)),
+        }
+    }
+}
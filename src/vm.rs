@@ -0,0 +1,1211 @@
+use crate::chunk::Closure;
+use crate::chunk::Function;
+use crate::chunk::OpCode;
+use crate::chunk::Permissions;
+use crate::chunk::SourceMap;
+use crate::chunk::Symbol;
+use crate::chunk::UpValue;
+use crate::chunk::Value;
+use crate::diagnostics::Diagnostic;
+use crate::diagnostics::ErrorCode;
+use crate::diagnostics::Frame;
+use crate::diagnostics::HumanReporter;
+use crate::diagnostics::Reporter;
+use crate::diagnostics::Style;
+use crate::interrupt;
+use crate::stats::Stats;
+use crate::trace::Trace;
+use log::debug;
+use log::trace;
+use num_bigint::BigInt;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+use std::time::Instant;
+
+#[derive(Debug)]
+struct CallStack {
+    closure: Rc<Closure>,
+    ip: usize,
+    offset: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct VmConfig {
+    pub max_instructions: Option<u64>,
+    pub max_wall_time: Option<Duration>,
+    pub max_frames: usize,
+    pub max_stack: usize,
+    pub on_tick: Option<OnTick>,
+    // validate every instruction and frame as it runs, see `check_instruction`
+    pub checked: bool,
+    // natives needing a capability not granted here fail with a runtime error
+    pub permissions: Permissions,
+    // '+' of a string and a number concatenates the number as print shows it
+    pub lenient_concat: bool,
+    // arithmetic on finite numbers giving infinity or NaN is an error
+    pub strict_math: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tick {
+    // `every` more instructions have run, `executed` in total
+    Instructions { executed: u64 },
+    // a Lox function was entered, nesting `depth` frames deep
+    Call { depth: usize },
+}
+
+// Lets an embedder run code while a script executes, e.g. to pump an event
+// loop or update a progress bar. The callback fires every `every`
+// instructions (never if 0) and on each call to a Lox function; returning
+// false cancels the script with an Interrupted error.
+#[derive(Clone)]
+pub struct OnTick {
+    pub every: u64,
+    pub callback: Rc<dyn Fn(Tick) -> bool>,
+}
+
+impl fmt::Debug for OnTick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OnTick(every {})", self.every)
+    }
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            max_instructions: None,
+            max_wall_time: None,
+            max_frames: 1024,
+            max_stack: 1024 * 256,
+            on_tick: None,
+            checked: cfg!(debug_assertions),
+            permissions: Permissions::default(),
+            lenient_concat: false,
+            strict_math: false,
+        }
+    }
+}
+
+pub struct VM {
+    frames: Vec<CallStack>,
+    stack: Vec<Value>,
+    pub reporter: Rc<dyn Reporter>,
+    // names the sources of the script in stack traces
+    pub sources: Rc<SourceMap>,
+    pub stats: Option<Stats>,
+    pub trace: Option<Trace>,
+    // when set, printed values are collected here instead of going to stdout
+    pub output: Option<String>,
+    config: VmConfig,
+    executed: u64,
+    started: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InterpretResult {
+    Ok,
+    RuntimeError,
+    LimitExceeded,
+    Interrupted,
+}
+
+impl VM {
+    pub fn new(script: impl Into<Rc<Function>>, config: VmConfig) -> Self {
+        VM {
+            frames: vec![CallStack {
+                closure: Rc::new(Closure {
+                    function: script.into(),
+                    upvalues: Box::new([]),
+                }),
+                offset: 0,
+                ip: 0,
+            }],
+            stack: vec![],
+            reporter: Rc::new(HumanReporter {
+                style: Style::plain(),
+            }),
+            sources: Rc::new(SourceMap::new()),
+            stats: None,
+            trace: None,
+            output: None,
+            config,
+            executed: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    fn push(&mut self, v: Value) {
+        self.stack.push(v);
+    }
+
+    fn frame(&self) -> &CallStack {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallStack {
+        self.frames.last_mut().unwrap()
+    }
+
+    pub fn run(&mut self) -> InterpretResult {
+        debug!("running {}", self.frame().closure.function.name);
+        let result = self.execute();
+        debug!(
+            "finished with {:?} after {} instructions in {:?}",
+            result,
+            self.executed,
+            self.started.elapsed()
+        );
+        result
+    }
+
+    fn execute(&mut self) -> InterpretResult {
+        loop {
+            let instruction = self.read_byte();
+            if let Some(stats) = &mut self.stats {
+                let frame = self.frames.last().unwrap();
+                stats.record(instruction, &frame.closure.function.name);
+            }
+            if interrupt::requested() {
+                self.runtime_error(ErrorCode::Interrupted, "Interrupted.");
+                return InterpretResult::Interrupted;
+            }
+            if !self.within_limits() {
+                return InterpretResult::LimitExceeded;
+            }
+            if let Some(on_tick) = &self.config.on_tick {
+                if self.executed.is_multiple_of(on_tick.every) {
+                    let executed = self.executed;
+                    if !self.tick(Tick::Instructions { executed }) {
+                        return InterpretResult::Interrupted;
+                    }
+                }
+            }
+            if let Some(trace) = &mut self.trace {
+                let depth = self.frames.len() as u32;
+                let ip = (self.frames.last().unwrap().ip - 1) as u32;
+                if let Err(msg) = trace.instruction(depth, ip, instruction) {
+                    self.runtime_error(ErrorCode::ReplayDiverged, &msg);
+                    return InterpretResult::RuntimeError;
+                }
+            }
+            let checked = if self.config.checked {
+                match self.check_instruction() {
+                    Ok(effect) => Some((self.stack.len(), self.frames.len(), effect)),
+                    Err(msg) => {
+                        self.runtime_error(ErrorCode::BrokenInvariant, &msg);
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+            } else {
+                None
+            };
+            let op = match OpCode::try_from(instruction) {
+                Ok(op) => op,
+                Err(op) => {
+                    self.runtime_error(
+                        ErrorCode::BrokenInvariant,
+                        &format!("Unknown opcode {}.", op),
+                    );
+                    return InterpretResult::RuntimeError;
+                }
+            };
+            match op {
+                OpCode::Return => {
+                    let v = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    // here lies our garbage collector!
+                    if self.frames.is_empty() {
+                        self.stack.truncate(0);
+                        return InterpretResult::Ok;
+                    }
+                    self.stack.truncate(frame.offset - 1);
+                    self.push(v);
+                }
+                OpCode::Constant => {
+                    let index = self.read_u32();
+                    let constant =
+                        self.frame().closure.function.chunk.constants[index as usize].clone();
+                    self.push(constant);
+                }
+                OpCode::Closure => {
+                    let index = self.read_u32();
+                    let function =
+                        self.frame().closure.function.chunk.functions[index as usize].clone();
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count as usize);
+                    // a recursive local function captures the slot it is
+                    // about to be stored in, which is not on the stack yet
+                    let mut own_slot = None;
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.read_bool();
+                        let index = self.read_u32();
+                        let slot = self.frame().offset + index as usize;
+                        if is_local && slot == self.stack.len() {
+                            let cell =
+                                own_slot.get_or_insert_with(|| Rc::new(RefCell::new(Value::nil())));
+                            upvalues.push(UpValue {
+                                location: cell.clone(),
+                            });
+                        } else if is_local {
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.frame().closure.upvalues[index as usize].clone());
+                        }
+                    }
+                    let closure_value = Value::closure(function, upvalues);
+                    match own_slot {
+                        Some(cell) => {
+                            *cell.borrow_mut() = closure_value;
+                            self.push(Value::Lifted(cell));
+                        }
+                        None => self.push(closure_value),
+                    }
+                }
+                OpCode::Divide => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error(
+                            ErrorCode::OperandsNotNumbers,
+                            "Operands must be numbers.",
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop();
+                    let a = self.pop();
+                    let quotient = a.as_number() / b.as_number();
+                    if let Err(msg) = self.check_finite(&a, &b, quotient, true) {
+                        self.runtime_error(ErrorCode::NonFiniteNumber, &msg);
+                        return InterpretResult::RuntimeError;
+                    }
+                    self.push(Value::from_number(quotient));
+                }
+                OpCode::Add => {
+                    let (a, b) = (self.peek(1), self.peek(0));
+                    let mixed =
+                        (a.is_string() && b.is_number()) || (a.is_number() && b.is_string());
+                    if a.is_string() && b.is_string() || mixed && self.config.lenient_concat {
+                        self.concatenate();
+                    } else if a.is_number() && b.is_number() {
+                        if let Err(msg) =
+                            self.arithmetic(i64::checked_add, |a, b| a + b, |a, b| a + b)
+                        {
+                            self.runtime_error(ErrorCode::NonFiniteNumber, &msg);
+                            return InterpretResult::RuntimeError;
+                        }
+                    } else {
+                        let hint = if mixed {
+                            " Convert the number with str()."
+                        } else {
+                            ""
+                        };
+                        self.runtime_error(
+                            ErrorCode::OperandsNotAddable,
+                            &format!(
+                                "Operands must be two numbers or two strings. Received: {} and {}.{}",
+                                self.peek(0).print(),
+                                self.peek(1).print(),
+                                hint
+                            ),
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                OpCode::Negate => {
+                    if !self.peek(0).is_number() {
+                        self.runtime_error(
+                            ErrorCode::OperandNotNumber,
+                            "Operand must be a number.",
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                    let neg = self.pop();
+                    match neg.is_int().then(|| neg.as_int().checked_neg()).flatten() {
+                        Some(i) => self.push(Value::from_int(i)),
+                        None if neg.is_integer() => self.push(Value::big_int(-neg.as_big_int())),
+                        None => self.push(Value::from_number(-neg.as_number())),
+                    }
+                }
+                OpCode::Multiply => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error(
+                            ErrorCode::OperandsNotNumbers,
+                            "Operands must be numbers.",
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                    if let Err(msg) = self.arithmetic(i64::checked_mul, |a, b| a * b, |a, b| a * b)
+                    {
+                        self.runtime_error(ErrorCode::NonFiniteNumber, &msg);
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                OpCode::Substract => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error(
+                            ErrorCode::OperandsNotNumbers,
+                            "Operands must be numbers.",
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                    if let Err(msg) = self.arithmetic(i64::checked_sub, |a, b| a - b, |a, b| a - b)
+                    {
+                        self.runtime_error(ErrorCode::NonFiniteNumber, &msg);
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                OpCode::Not => {
+                    if !self.peek(0).is_bool() {
+                        self.runtime_error(ErrorCode::OperandNotBool, "Operand must be a bool.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop();
+                    self.push(Value::from_bool(!b.as_bool()));
+                }
+                OpCode::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::from_bool(a == b));
+                }
+                OpCode::Less => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error(
+                            ErrorCode::OperandsNotNumbers,
+                            "Operands must be numbers.",
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::from_bool(matches!(
+                        a.compare_numbers(&b),
+                        Some(Ordering::Less)
+                    )));
+                }
+                OpCode::LessEqual => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error(
+                            ErrorCode::OperandsNotNumbers,
+                            "Operands must be numbers.",
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::from_bool(matches!(
+                        a.compare_numbers(&b),
+                        Some(Ordering::Less | Ordering::Equal)
+                    )));
+                }
+                OpCode::Greater => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error(
+                            ErrorCode::OperandsNotNumbers,
+                            "Operands must be numbers.",
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::from_bool(matches!(
+                        a.compare_numbers(&b),
+                        Some(Ordering::Greater)
+                    )));
+                }
+                OpCode::GreaterEqual => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error(
+                            ErrorCode::OperandsNotNumbers,
+                            "Operands must be numbers.",
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::from_bool(matches!(
+                        a.compare_numbers(&b),
+                        Some(Ordering::Greater | Ordering::Equal)
+                    )));
+                }
+                OpCode::Index => {
+                    let target = self.peek(1);
+                    if !target.is_string() && !target.is_tuple() && !target.is_bytes() {
+                        self.runtime_error(
+                            ErrorCode::InvalidIndex,
+                            "Only strings, tuples and bytes can be indexed.",
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                    let index = self.peek(0);
+                    if !index.is_number() || index.as_number().fract() != 0.0 {
+                        self.runtime_error(ErrorCode::InvalidIndex, "Index must be an integer.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let i = self.pop().as_number();
+                    let target = self.pop();
+                    let (element, len) = if target.is_tuple() {
+                        let values = target.as_tuple();
+                        let len = values.len();
+                        (values.into_iter().nth(i as usize), len)
+                    } else if target.is_bytes() {
+                        let bytes = target.as_bytes();
+                        let byte = bytes.get(i as usize);
+                        (byte.map(|b| Value::from_int(*b as i64)), bytes.len())
+                    } else {
+                        // strings are indexed by code point, not by byte
+                        let s = target.as_str();
+                        let c = s.chars().nth(i as usize);
+                        (c.map(|c| Value::string(&c.to_string())), s.chars().count())
+                    };
+                    match element {
+                        Some(element) if i >= 0.0 => self.push(element),
+                        _ => {
+                            self.runtime_error(
+                                ErrorCode::InvalidIndex,
+                                &format!("Index {} out of range for length {}.", i, len),
+                            );
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::Contains => {
+                    let container = self.peek(0);
+                    let found = if container.is_tuple() {
+                        container.as_tuple().contains(self.peek(1))
+                    } else if container.is_string() && self.peek(1).is_string() {
+                        container.as_str().contains(&self.peek(1).as_str())
+                    } else {
+                        self.runtime_error(
+                            ErrorCode::NotAContainer,
+                            "Operands of 'in' must be two strings, or a value and a tuple.",
+                        );
+                        return InterpretResult::RuntimeError;
+                    };
+                    self.pop();
+                    self.pop();
+                    self.push(Value::from_bool(found));
+                }
+                OpCode::Tuple => {
+                    let count = self.read_u32() as usize;
+                    let values = self.stack.split_off(self.stack.len() - count);
+                    self.push(Value::tuple(values));
+                }
+                OpCode::TupleSpread => {
+                    let count = self.read_u32();
+                    let index = self.read_u32();
+                    let spreads =
+                        self.frame().closure.function.chunk.constants[index as usize].as_tuple();
+                    let count = match self.spread(count, &spreads) {
+                        Some(count) => count as usize,
+                        None => return InterpretResult::RuntimeError,
+                    };
+                    let values = self.stack.split_off(self.stack.len() - count);
+                    self.push(Value::tuple(values));
+                }
+                OpCode::Unpack => {
+                    let count = self.read_u32() as usize;
+                    let tuple = self.pop();
+                    if !tuple.is_tuple() || tuple.as_tuple().len() != count {
+                        self.runtime_error(
+                            ErrorCode::CannotUnpack,
+                            &format!("Expected a tuple of {} values.", count),
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                    self.stack.extend(tuple.as_tuple());
+                }
+                OpCode::Record => {
+                    let count = self.read_u32() as usize;
+                    let symbols: Vec<Symbol> = (0..count)
+                        .map(|_| Symbol::from_id(self.read_u32()).unwrap())
+                        .collect();
+                    let values = self.stack.split_off(self.stack.len() - count);
+                    self.push(Value::record(symbols.into_iter().zip(values).collect()));
+                }
+                OpCode::GetField => {
+                    let symbol = Symbol::from_id(self.read_u32()).unwrap();
+                    if !self.peek(0).is_record() {
+                        self.runtime_error(ErrorCode::InvalidField, "Only records have fields.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    match self.pop().field(symbol) {
+                        Some(value) => self.push(value),
+                        None => {
+                            self.runtime_error(
+                                ErrorCode::InvalidField,
+                                &format!("Undefined field '{}'.", symbol.name()),
+                            );
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::Print => {
+                    let v = self.pop().print();
+                    self.write_line(&v);
+                }
+                OpCode::Trace => {
+                    let frame = self.frame();
+                    let line = frame.closure.function.chunk.locations[frame.ip - 1].line;
+                    let v = self.pop().print();
+                    self.write_line(&format!("[line {}] {}", line, v));
+                }
+                OpCode::Nil => {
+                    self.push(Value::Nil);
+                }
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::JumpIfFalse => {
+                    let jump = self.read_u32();
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += jump as usize;
+                    }
+                }
+                OpCode::Jump => {
+                    let jump = self.read_u32();
+                    self.frame_mut().ip += jump as usize;
+                }
+                OpCode::Loop => {
+                    let jump = self.read_u32();
+                    self.frame_mut().ip -= jump as usize;
+                }
+                OpCode::GetLocal => {
+                    let index = self.read_u32();
+                    let value = match &self.stack[self.frame().offset + index as usize] {
+                        Value::Lifted(lifted) => lifted.borrow().clone(),
+                        value => value.clone(),
+                    };
+                    self.push(value);
+                }
+                OpCode::SetLocal => {
+                    let index = self.read_u32();
+                    let offset = self.frame().offset;
+                    let value = self.peek(0).clone();
+                    // a captured local lives in its cell, shared with closures
+                    match &self.stack[offset + index as usize] {
+                        Value::Lifted(lifted) => *lifted.borrow_mut() = value,
+                        _ => self.stack[offset + index as usize] = value,
+                    }
+                }
+                OpCode::GetUpvalue => {
+                    let slot = self.read_u32();
+                    let value = self.frame().closure.upvalues[slot as usize]
+                        .location
+                        .borrow()
+                        .clone();
+                    self.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let slot = self.read_u32();
+                    *self.frame().closure.upvalues[slot as usize]
+                        .location
+                        .borrow_mut() = self.peek(0).clone();
+                }
+                OpCode::Call => {
+                    let args_c = self.read_u32();
+                    if let Err(result) = self.call_and_tick(args_c) {
+                        return result;
+                    }
+                }
+                OpCode::CallNamed => {
+                    let args_c = self.read_u32();
+                    let index = self.read_u32();
+                    let names =
+                        self.frame().closure.function.chunk.constants[index as usize].as_tuple();
+                    let args_c = match self.order_arguments(args_c, &names) {
+                        Some(args_c) => args_c,
+                        None => return InterpretResult::RuntimeError,
+                    };
+                    if let Err(result) = self.call_and_tick(args_c) {
+                        return result;
+                    }
+                }
+                OpCode::CallSpread => {
+                    let args_c = self.read_u32();
+                    let spreads = self.read_u32();
+                    let names = self.read_u32();
+                    let constants = &self.frame().closure.function.chunk.constants;
+                    let spreads = constants[spreads as usize].as_tuple();
+                    let names = constants[names as usize].as_tuple();
+                    // named arguments come after all the spread ones, so
+                    // the spreads are expanded first
+                    let args_c = match self.spread(args_c, &spreads) {
+                        Some(args_c) => args_c,
+                        None => return InterpretResult::RuntimeError,
+                    };
+                    let args_c = if names.is_empty() {
+                        args_c
+                    } else {
+                        match self.order_arguments(args_c, &names) {
+                            Some(args_c) => args_c,
+                            None => return InterpretResult::RuntimeError,
+                        }
+                    };
+                    if let Err(result) = self.call_and_tick(args_c) {
+                        return result;
+                    }
+                }
+                OpCode::Debug => {
+                    self.debug();
+                }
+            }
+            if let Some((stack, frames, effect)) = checked {
+                if let Err(msg) = self.check_effect(op, stack, frames, effect) {
+                    self.runtime_error(ErrorCode::BrokenInvariant, &msg);
+                    return InterpretResult::RuntimeError;
+                }
+            }
+        }
+    }
+
+    // Validates the operands of the instruction about to run against its
+    // chunk, stack window and closure, and returns its stack effect. Only
+    // compiled bytecode runs, so any failure is a bug in rlox.
+    fn check_instruction(&self) -> Result<(usize, usize), String> {
+        let frame = self.frame();
+        let chunk = &frame.closure.function.chunk;
+        let offset = frame.ip - 1;
+        let op = OpCode::try_from(chunk.code[offset])
+            .map_err(|op| format!("Unknown opcode {} at offset {}.", op, offset))?;
+        let name = format!("{:?} at offset {}", op, offset);
+        let operand = |at: usize| {
+            chunk
+                .code
+                .get(at..at + 4)
+                .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+                .ok_or_else(|| format!("{} has an operand past the end of the chunk.", name))
+        };
+        let window = self
+            .stack
+            .len()
+            .checked_sub(frame.offset)
+            .ok_or_else(|| format!("{} runs in a frame starting above the stack.", name))?;
+        match op {
+            OpCode::Constant => {
+                let index = operand(offset + 1)?;
+                if index >= chunk.constants.len() {
+                    return Err(format!("{} reads missing constant {}.", name, index));
+                }
+            }
+            OpCode::Closure => {
+                let index = operand(offset + 1)?;
+                let function = chunk
+                    .functions
+                    .get(index)
+                    .ok_or_else(|| format!("{} wraps missing function {}.", name, index))?;
+                for i in 0..function.upvalue_count as usize {
+                    let capture = offset + 5 + 5 * i;
+                    let index = operand(capture + 1)?;
+                    let in_bounds = if chunk.code[capture] != 0 {
+                        // the recursive capture of the slot being declared
+                        index <= window
+                    } else {
+                        index < frame.closure.upvalues.len()
+                    };
+                    if !in_bounds {
+                        return Err(format!("{} captures missing slot {}.", name, index));
+                    }
+                }
+            }
+            OpCode::GetLocal | OpCode::SetLocal => {
+                let index = operand(offset + 1)?;
+                if index >= window {
+                    return Err(format!("{} reads local {} of {}.", name, index, window));
+                }
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                let index = operand(offset + 1)?;
+                if index >= frame.closure.upvalues.len() {
+                    return Err(format!("{} reads missing upvalue {}.", name, index));
+                }
+            }
+            OpCode::Jump | OpCode::JumpIfFalse => {
+                let jump = operand(offset + 1)?;
+                if offset + 5 + jump > chunk.code.len() {
+                    return Err(format!("{} jumps past the end of the chunk.", name));
+                }
+            }
+            OpCode::Loop => {
+                let jump = operand(offset + 1)?;
+                if jump > offset + 5 {
+                    return Err(format!("{} jumps before the start of the chunk.", name));
+                }
+            }
+            OpCode::Call | OpCode::Tuple | OpCode::Unpack => {
+                operand(offset + 1)?;
+            }
+            OpCode::CallNamed => {
+                operand(offset + 1)?;
+                let index = operand(offset + 5)?;
+                if !chunk.constants.get(index).is_some_and(Value::is_tuple) {
+                    return Err(format!("{} has no tuple of names.", name));
+                }
+            }
+            OpCode::CallSpread => {
+                operand(offset + 1)?;
+                for at in [offset + 5, offset + 9] {
+                    let index = operand(at)?;
+                    if !chunk.constants.get(index).is_some_and(Value::is_tuple) {
+                        return Err(format!("{} has no tuple of positions or names.", name));
+                    }
+                }
+            }
+            OpCode::TupleSpread => {
+                operand(offset + 1)?;
+                let index = operand(offset + 5)?;
+                if !chunk.constants.get(index).is_some_and(Value::is_tuple) {
+                    return Err(format!("{} has no tuple of positions.", name));
+                }
+            }
+            OpCode::Record => {
+                let count = operand(offset + 1)?;
+                for i in 0..count {
+                    let id = operand(offset + 5 + 4 * i)?;
+                    if Symbol::from_id(id as u32).is_none() {
+                        return Err(format!("{} names an unknown field.", name));
+                    }
+                }
+            }
+            OpCode::GetField => {
+                let id = operand(offset + 1)?;
+                if Symbol::from_id(id as u32).is_none() {
+                    return Err(format!("{} names an unknown field.", name));
+                }
+            }
+            OpCode::Return if self.frames.len() > 1 && frame.offset == 0 => {
+                return Err(format!("{} leaves a frame with no callee slot.", name));
+            }
+            _ => {}
+        }
+        let (pops, pushes) = chunk.stack_effect(offset);
+        if pops > window {
+            return Err(format!(
+                "{} pops {} values from a frame holding {}.",
+                name, pops, window
+            ));
+        }
+        Ok((pops, pushes))
+    }
+
+    // Checks that the instruction that just ran left the stack as its
+    // effect says, and that a call set up its callee's frame correctly.
+    fn check_effect(
+        &self,
+        op: OpCode,
+        stack: usize,
+        frames: usize,
+        (pops, pushes): (usize, usize),
+    ) -> Result<(), String> {
+        match op {
+            // the stack only settles once the script's frame returns
+            OpCode::Return => Ok(()),
+            OpCode::Call | OpCode::CallNamed | OpCode::CallSpread if self.frames.len() > frames => {
+                let frame = self.frame();
+                let arity = frame.closure.function.arity as usize;
+                if frame.offset == 0 || frame.offset + arity != self.stack.len() {
+                    Err(format!(
+                        "Call to {} set up a frame at {} for {} arguments on a stack of {}.",
+                        frame.closure.function.name,
+                        frame.offset,
+                        arity,
+                        self.stack.len()
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+            // how many values a spread expands to is only known at runtime
+            OpCode::CallSpread | OpCode::TupleSpread => Ok(()),
+            op => {
+                if stack - pops + pushes == self.stack.len() {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "{:?} left {} values on the stack instead of {}.",
+                        op,
+                        self.stack.len(),
+                        stack - pops + pushes
+                    ))
+                }
+            }
+        }
+    }
+
+    fn within_limits(&mut self) -> bool {
+        self.executed += 1;
+        if let Some(max) = self.config.max_instructions {
+            if self.executed > max {
+                self.runtime_error(
+                    ErrorCode::LimitExceeded,
+                    &format!("Instruction limit of {} exceeded.", max),
+                );
+                return false;
+            }
+        }
+        // reading the clock on every instruction would dominate dispatch
+        if self.executed.is_multiple_of(1024) {
+            if let Some(max) = self.config.max_wall_time {
+                if self.started.elapsed() > max {
+                    self.runtime_error(
+                        ErrorCode::LimitExceeded,
+                        &format!("Time limit of {}ms exceeded.", max.as_millis()),
+                    );
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn tick(&mut self, event: Tick) -> bool {
+        let on_tick = match &self.config.on_tick {
+            Some(on_tick) => on_tick.clone(),
+            None => return true,
+        };
+        if (on_tick.callback)(event) {
+            true
+        } else {
+            self.runtime_error(ErrorCode::Interrupted, "Cancelled by the host.");
+            false
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        match &mut self.output {
+            Some(output) => {
+                output.push_str(line);
+                output.push('\n');
+            }
+            None => println!("{}", line),
+        }
+    }
+
+    fn debug(&self) {
+        print!("stack: ");
+        for v in &self.stack {
+            print!("[{}] ", v.print());
+        }
+        println!();
+    }
+
+    fn capture_upvalue(&mut self, i: usize) -> UpValue {
+        if let Value::Lifted(lifted) = &self.stack[i] {
+            UpValue {
+                location: lifted.clone(),
+            }
+        } else {
+            let lifted = Rc::new(RefCell::new(self.stack[i].clone()));
+            self.stack[i] = Value::Lifted(lifted.clone());
+            UpValue { location: lifted }
+        }
+    }
+
+    // The operand readers look the current frame up once per read: they
+    // run for nearly every instruction.
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frames.last_mut().unwrap();
+        let byte = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u32(&mut self) -> u32 {
+        let frame = self.frames.last_mut().unwrap();
+        let code = &frame.closure.function.chunk.code;
+        let ip = frame.ip;
+        let operand = u32::from_be_bytes([code[ip], code[ip + 1], code[ip + 2], code[ip + 3]]);
+        frame.ip += 4;
+        operand
+    }
+
+    fn read_bool(&mut self) -> bool {
+        self.read_byte() != 0
+    }
+
+    fn call(&mut self, argc: u32) -> bool {
+        let f = self.peek(argc as usize);
+        if f.is_closure() {
+            let function = f.as_function();
+            if function.arity != argc {
+                self.wrong_arity(&function.name, &arguments(function.arity), argc);
+                false
+            } else if self.frames.len() >= self.config.max_frames
+                || self.stack.len() >= self.config.max_stack
+            {
+                self.runtime_error(ErrorCode::StackOverflow, "Stack overflow.");
+                false
+            } else {
+                let closure = f.as_closure();
+                if let Some(trace) = &mut self.trace {
+                    if let Err(msg) = trace.call(&function.name, argc) {
+                        self.runtime_error(ErrorCode::ReplayDiverged, &msg);
+                        return false;
+                    }
+                }
+                trace!("call {} at depth {}", function.name, self.frames.len());
+                self.frames.push(CallStack {
+                    closure,
+                    ip: 0,
+                    offset: self.stack.len() - argc as usize,
+                });
+                true
+            }
+        } else if f.is_native() {
+            let native = f.as_native();
+            if native.variadic && argc < native.arity {
+                let expected = format!("at least {}", arguments(native.arity));
+                self.wrong_arity(&native.name, &expected, argc);
+                return false;
+            } else if !native.variadic && native.arity != argc {
+                self.wrong_arity(&native.name, &arguments(native.arity), argc);
+                return false;
+            }
+            if let Some(capability) = native.capability {
+                if !self.config.permissions.allows(capability) {
+                    self.runtime_error(
+                        ErrorCode::PermissionDenied,
+                        &format!(
+                            "{}() needs the '{}' permission.",
+                            native.name,
+                            capability.name()
+                        ),
+                    );
+                    return false;
+                }
+            }
+            trace!("call native {}", native.name);
+            let args = self.stack.split_off(self.stack.len() - argc as usize);
+            self.pop();
+            if let Some(trace) = &mut self.trace {
+                if let Err(msg) = trace.call(&native.name, argc) {
+                    self.runtime_error(ErrorCode::ReplayDiverged, &msg);
+                    return false;
+                }
+            }
+            let result = match &mut self.trace {
+                Some(trace) => trace.native(&args, || (native.function)(&args)),
+                None => Ok((native.function)(&args)),
+            };
+            let result = match result {
+                Ok(result) => result,
+                Err(msg) => {
+                    self.runtime_error(ErrorCode::ReplayDiverged, &msg);
+                    return false;
+                }
+            };
+            match result {
+                Ok(v) => {
+                    self.push(v);
+                    true
+                }
+                Err(msg) => {
+                    self.runtime_error(ErrorCode::NativeError, &msg);
+                    false
+                }
+            }
+        } else {
+            self.runtime_error(
+                ErrorCode::NotCallable,
+                "Can only call functions and classes.",
+            );
+            false
+        }
+    }
+
+    // Reports a call to `name` with `argc` arguments instead of `expected`.
+    fn wrong_arity(&mut self, name: &str, expected: &str, argc: u32) {
+        self.runtime_error(
+            ErrorCode::WrongArity,
+            &format!("{}() expects {} but got {}.", name, expected, argc),
+        );
+    }
+
+    // Calls the callee below the top `argc` values and reports the new
+    // frame, if any, to the host.
+    fn call_and_tick(&mut self, argc: u32) -> Result<(), InterpretResult> {
+        let depth = self.frames.len();
+        if !self.call(argc) {
+            return Err(InterpretResult::RuntimeError);
+        }
+        if self.frames.len() > depth {
+            let depth = self.frames.len();
+            if !self.tick(Tick::Call { depth }) {
+                return Err(InterpretResult::Interrupted);
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces each of the top `count` values whose position is listed in
+    // `spreads` by the elements of that tuple, and returns the new count.
+    fn spread(&mut self, count: u32, spreads: &[Value]) -> Option<u32> {
+        let start = self.stack.len() - count as usize;
+        let values = self.stack.split_off(start);
+        for (i, value) in values.into_iter().enumerate() {
+            if !spreads.contains(&Value::from_number(i as f64)) {
+                self.stack.push(value);
+            } else if value.is_tuple() {
+                self.stack.extend(value.as_tuple());
+            } else {
+                self.runtime_error(ErrorCode::CannotUnpack, "Only tuples can be spread.");
+                return None;
+            }
+        }
+        Some((self.stack.len() - start) as u32)
+    }
+
+    // Moves the arguments of a call with named arguments into parameter
+    // order, so that it can proceed as a positional call. Returns the
+    // resulting argument count.
+    fn order_arguments(&mut self, argc: u32, names: &[Value]) -> Option<u32> {
+        let f = self.peek(argc as usize);
+        if !f.is_closure() {
+            self.runtime_error(
+                ErrorCode::BadNamedArgument,
+                "Only Lox functions accept named arguments.",
+            );
+            return None;
+        }
+        let function = f.as_function();
+        let positional = argc as usize - names.len();
+        if positional > function.params.len() {
+            self.wrong_arity(&function.name, &arguments(function.arity), argc);
+            return None;
+        }
+        let mut args = self.stack.split_off(self.stack.len() - argc as usize);
+        let named = args.split_off(positional);
+        let mut ordered: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+        ordered.resize(function.params.len(), None);
+        for (name, value) in names.iter().zip(named) {
+            let name = name.as_str();
+            let error = match function.params.iter().position(|p| *p == name) {
+                Some(i) if ordered[i].is_none() => {
+                    ordered[i] = Some(value);
+                    continue;
+                }
+                Some(_) => format!("Argument '{}' of {}() is given twice.", name, function.name),
+                None => format!("{}() has no parameter named '{}'.", function.name, name),
+            };
+            self.runtime_error(ErrorCode::BadNamedArgument, &error);
+            return None;
+        }
+        if let Some(i) = ordered.iter().position(Option::is_none) {
+            self.runtime_error(
+                ErrorCode::BadNamedArgument,
+                &format!(
+                    "Missing argument '{}' of {}().",
+                    function.params[i], function.name
+                ),
+            );
+            return None;
+        }
+        self.stack.extend(ordered.into_iter().flatten());
+        Some(function.arity)
+    }
+
+    // Pops two numbers and pushes the result of `int` when both are integers
+    // and it does not overflow, of `big` when they are integers otherwise,
+    // or of `float`.
+    fn arithmetic(
+        &mut self,
+        int: fn(i64, i64) -> Option<i64>,
+        big: fn(BigInt, BigInt) -> BigInt,
+        float: fn(f64, f64) -> f64,
+    ) -> Result<(), String> {
+        let b = self.pop();
+        let a = self.pop();
+        let exact = (a.is_int() && b.is_int())
+            .then(|| int(a.as_int(), b.as_int()))
+            .flatten();
+        match exact {
+            Some(i) => self.push(Value::from_int(i)),
+            None if a.is_integer() && b.is_integer() => {
+                self.push(Value::big_int(big(a.as_big_int(), b.as_big_int())))
+            }
+            None => {
+                let result = float(a.as_number(), b.as_number());
+                self.check_finite(&a, &b, result, false)?;
+                self.push(Value::from_number(result));
+            }
+        }
+        Ok(())
+    }
+
+    // With `strict_math`, fails when finite operands gave infinity or NaN.
+    // Integers count as finite even when too big for an f64.
+    fn check_finite(
+        &self,
+        a: &Value,
+        b: &Value,
+        result: f64,
+        dividing: bool,
+    ) -> Result<(), String> {
+        let finite = |v: &Value| v.is_integer() || v.as_number().is_finite();
+        if !self.config.strict_math || result.is_finite() || !finite(a) || !finite(b) {
+            Ok(())
+        } else if dividing && b.as_number() == 0.0 {
+            Err("Division by zero.".to_string())
+        } else if result.is_nan() {
+            Err("Arithmetic produced NaN.".to_string())
+        } else {
+            Err("Arithmetic overflowed to infinity.".to_string())
+        }
+    }
+
+    fn concatenate(&mut self) {
+        let b = self.pop();
+        let mut a = self.pop().print();
+        a.push_str(&b.print());
+        self.push(Value::string(&a));
+    }
+
+    fn peek(&self, depth: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - depth]
+    }
+
+    fn runtime_error(&mut self, code: ErrorCode, msg: &str) {
+        let frames: Vec<Frame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                // ip is past the instruction being executed, or for a caller
+                // past its call; a frame cancelled on entry has not
+                // executed anything yet
+                let chunk = &frame.closure.function.chunk;
+                let instruction = chunk.instruction_at(frame.ip.saturating_sub(1));
+                let location = chunk.locations[instruction];
+                Frame {
+                    file: self.sources.name(location.file).map(str::to_string),
+                    line: location.line,
+                    column: location.column,
+                    function: frame.closure.function.name.clone(),
+                }
+            })
+            .collect();
+        self.reporter.report(&Diagnostic {
+            code,
+            file: frames[0].file.clone(),
+            line: frames[0].line,
+            column: frames[0].column,
+            message: msg.to_string(),
+            at: None,
+            frames,
+        });
+    }
+}
+
+// "1 argument", "2 arguments"
+fn arguments(count: u32) -> String {
+    if count == 1 {
+        "1 argument".to_string()
+    } else {
+        format!("{} arguments", count)
+    }
+}
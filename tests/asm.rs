@@ -0,0 +1,191 @@
+use rlox::asm;
+use rlox::asm::AsmError;
+use rlox::chunk::Function;
+use rlox::chunk::Location;
+use rlox::chunk::OpCode;
+use rlox::compiler::Parser;
+use rlox::diagnostics::CollectingReporter;
+use rlox::natives;
+use rlox::vm::InterpretResult;
+use rlox::vm::VmConfig;
+use rlox::vm::VM;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+// Writes compiled scripts as assembly and assembles them back, and checks
+// that hand-written assembly runs or is rejected with the line at fault.
+
+fn compile(source: &str) -> Function {
+    let mut parser = Parser::init(source);
+    parser.natives = natives::standard();
+    parser.compile().unwrap()
+}
+
+fn assemble(source: &str) -> Result<Function, AsmError> {
+    asm::parse(source, &natives::standard())
+}
+
+fn run(script: Function) -> (InterpretResult, String) {
+    let config = VmConfig {
+        checked: true,
+        ..VmConfig::default()
+    };
+    let mut vm = VM::new(script, config);
+    vm.output = Some(String::new());
+    let result = vm.run();
+    (result, vm.output.take().unwrap())
+}
+
+fn error(line: usize, message: &str) -> Result<Function, AsmError> {
+    Err(AsmError {
+        line,
+        message: message.to_string(),
+    })
+}
+
+#[test]
+fn round_trip() {
+    let script = compile(
+        r#"
+        /// Returns a function counting its calls.
+        fun counter() {
+          var i = 0;
+          fun count() { i = i + 1; return i; }
+          return count;
+        }
+        var c = counter();
+        c();
+        print c();
+        var r = {name: "a \ b", size: 1.5};
+        print r.name;
+        for (var k = 0; k < 3; k = k + 1) {
+          if (k == 1) print k; else print -k;
+        }
+        print (1, 0.1, 100000000000000000000, nil, true);
+        fun f(a, b) { return a - b; }
+        print f(b: 1, a: 5);
+        var t = (1, 2);
+        print f(...t);
+        print len("four");
+        "#,
+    );
+    let text = asm::write(&script).unwrap();
+    let assembled = assemble(&text).unwrap();
+    assert_eq!(asm::write(&assembled).unwrap(), text);
+    let expected = "2\na \\ b\n0\n1\n-2\n(1, 0.1, 100000000000000000000, nil, true)\n4\n-1\n4\n";
+    assert_eq!(run(script), (InterpretResult::Ok, expected.to_string()));
+    assert_eq!(run(assembled), (InterpretResult::Ok, expected.to_string()));
+}
+
+#[test]
+fn hand_written() {
+    let source = r#"
+        ; prints 3, 2 and 1
+        .function 0 "<script>" arity 0 upvalues 0
+        .constant int 3
+        .constant int 0
+        .constant int 1
+            OP_CONSTANT 0
+        top:
+            OP_GET_LOCAL 0
+            OP_CONSTANT 1
+            OP_GREATER
+            OP_JUMP_IF_FALSE done
+            OP_POP
+            OP_GET_LOCAL 0
+            OP_PRINT
+            OP_GET_LOCAL 0
+            OP_CONSTANT 2
+            OP_SUBSTRACT
+            OP_SET_LOCAL 0
+            OP_POP
+            OP_LOOP top
+        done:
+            OP_POP
+            OP_NIL
+            OP_RETURN
+        .end
+    "#;
+    let script = assemble(source).unwrap();
+    assert_eq!(run(script), (InterpretResult::Ok, "3\n2\n1\n".to_string()));
+}
+
+#[test]
+fn strings_keep_every_character() {
+    let source = ".function 0 \"<script>\" arity 0 upvalues 0\n\
+                  .constant string \"a \\\"quoted\\\" ; \\u{1b} \\\\ line\\n\"\n\
+                  OP_CONSTANT 0\nOP_PRINT\nOP_NIL\nOP_RETURN\n.end\n";
+    let script = assemble(source).unwrap();
+    let (result, output) = run(script.clone());
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(output, "a \"quoted\" ; \u{1b} \\ line\n\n");
+    assert_eq!(assemble(&asm::write(&script).unwrap()).unwrap(), script);
+}
+
+#[test]
+fn errors_name_their_line() {
+    let script = |code: &str| {
+        format!(
+            ".function 0 \"<script>\" arity 0 upvalues 0\n.constant nil\n{}\n.end\n",
+            code
+        )
+    };
+    assert_eq!(
+        assemble(&script("OP_PUSH 1")),
+        error(3, "Unknown instruction 'OP_PUSH'.")
+    );
+    assert_eq!(
+        assemble(&script("OP_JUMP nowhere")),
+        error(3, "Undefined label 'nowhere'.")
+    );
+    assert_eq!(
+        assemble(&script("back:\nOP_JUMP back")),
+        error(4, "OP_JUMP can only jump forward.")
+    );
+    assert_eq!(
+        assemble(&script("OP_CONSTANT 1")),
+        error(3, "Constant 1 is not declared.")
+    );
+    assert_eq!(
+        assemble(&script("OP_POP 1")),
+        error(3, "Unexpected text at the end of the line.")
+    );
+    assert_eq!(
+        assemble(".function 0 \"<script>\" arity 0 upvalues 0\nOP_NIL\n"),
+        error(2, "Expect '.end' after the last function.")
+    );
+    let unused = format!(
+        "{}.function 1 \"f\" arity 0 upvalues 0\n.end\n",
+        script("OP_NIL")
+    );
+    assert_eq!(assemble(&unused), error(5, "Function 1 is never used."));
+}
+
+// Bytes that are no opcode stop the VM with an error, checked or not.
+#[test]
+fn unknown_opcodes() {
+    assert_eq!(OpCode::try_from(255), Ok(OpCode::Debug));
+    assert_eq!(OpCode::try_from(200), Err(200));
+    assert_eq!(OpCode::from_name("OP_DEBUG"), Some(OpCode::Debug));
+    for (checked, message) in [
+        (true, "Unknown opcode 200 at offset 0."),
+        (false, "Unknown opcode 200."),
+    ] {
+        let mut script = Function::new(0, "<script>");
+        script.chunk.code.push(200);
+        script.chunk.locations.push(Location {
+            file: 0,
+            line: 1,
+            column: 1,
+        });
+        let config = VmConfig {
+            checked,
+            ..VmConfig::default()
+        };
+        let mut vm = VM::new(script, config);
+        let collected = Rc::new(CollectingReporter::default());
+        vm.reporter = collected.clone();
+        assert_eq!(vm.run(), InterpretResult::RuntimeError);
+        assert_eq!(collected.take()[0].message, message);
+    }
+}
@@ -0,0 +1,99 @@
+use rlox::chunk::Function;
+use rlox::chunk::Location;
+use rlox::chunk::OpCode;
+use rlox::chunk::Value;
+use rlox::vm::InterpretResult;
+use rlox::vm::VmConfig;
+use rlox::vm::VM;
+
+// Assembles bytecode through the public Chunk API, with no Lox source
+// involved, and runs it on a checked VM.
+
+const AT: Location = Location {
+    file: 0,
+    line: 1,
+    column: 1,
+};
+
+fn run(script: Function) -> (InterpretResult, String) {
+    let config = VmConfig {
+        checked: true,
+        ..VmConfig::default()
+    };
+    let mut vm = VM::new(script, config);
+    vm.output = Some(String::new());
+    let result = vm.run();
+    (result, vm.output.take().unwrap())
+}
+
+fn end(script: &mut Function) {
+    script.chunk.emit(OpCode::Nil, &[], AT);
+    script.chunk.emit(OpCode::Return, &[], AT);
+}
+
+#[test]
+fn arithmetic() {
+    let mut script = Function::new(0, "<script>");
+    let chunk = &mut script.chunk;
+    chunk.constant(Value::from_int(1), AT);
+    chunk.constant(Value::from_int(2), AT);
+    chunk.emit(OpCode::Add, &[], AT);
+    chunk.emit(OpCode::Print, &[], AT);
+    end(&mut script);
+    assert_eq!(run(script), (InterpretResult::Ok, "3\n".to_string()));
+}
+
+// var i = 0; while (i < 3) { print i; i = i + 1; }
+#[test]
+fn loops() {
+    let mut script = Function::new(0, "<script>");
+    let chunk = &mut script.chunk;
+    chunk.constant(Value::from_int(0), AT);
+    let start = chunk.size();
+    chunk.emit(OpCode::GetLocal, &[0], AT);
+    chunk.constant(Value::from_int(3), AT);
+    chunk.emit(OpCode::Less, &[], AT);
+    let exit = chunk.emit_jump(OpCode::JumpIfFalse, AT);
+    chunk.emit(OpCode::Pop, &[], AT);
+    chunk.emit(OpCode::GetLocal, &[0], AT);
+    chunk.emit(OpCode::Print, &[], AT);
+    chunk.emit(OpCode::GetLocal, &[0], AT);
+    chunk.constant(Value::from_int(1), AT);
+    chunk.emit(OpCode::Add, &[], AT);
+    chunk.emit(OpCode::SetLocal, &[0], AT);
+    chunk.emit(OpCode::Pop, &[], AT);
+    chunk.emit_loop(start, AT);
+    chunk.patch_jump(exit);
+    chunk.emit(OpCode::Pop, &[], AT);
+    chunk.emit(OpCode::Pop, &[], AT);
+    end(&mut script);
+    assert_eq!(run(script), (InterpretResult::Ok, "0\n1\n2\n".to_string()));
+}
+
+#[test]
+fn calls() {
+    let mut double = Function::new(1, "double");
+    double.chunk.emit(OpCode::GetLocal, &[0], AT);
+    double.chunk.constant(Value::from_int(2), AT);
+    double.chunk.emit(OpCode::Multiply, &[], AT);
+    double.chunk.emit(OpCode::Return, &[], AT);
+
+    let mut script = Function::new(0, "<script>");
+    let chunk = &mut script.chunk;
+    let index = chunk.add_function(double.into());
+    chunk.emit(OpCode::Closure, &[index], AT);
+    chunk.constant(Value::from_int(21), AT);
+    chunk.emit(OpCode::Call, &[1], AT);
+    chunk.emit(OpCode::Print, &[], AT);
+    end(&mut script);
+    assert_eq!(run(script), (InterpretResult::Ok, "42\n".to_string()));
+}
+
+// The checked VM reports malformed bytecode instead of misbehaving.
+#[test]
+fn malformed() {
+    let mut script = Function::new(0, "<script>");
+    script.chunk.emit(OpCode::Constant, &[7], AT);
+    end(&mut script);
+    assert_eq!(run(script).0, InterpretResult::RuntimeError);
+}
@@ -0,0 +1,46 @@
+use rlox::compiler::Scanner;
+use rlox::compiler::TokenType;
+
+// Scans sources and checks the line and column each token starts at.
+// Columns count chars, not bytes.
+
+fn positions(source: &str) -> Vec<(&str, usize, usize)> {
+    let mut scanner = Scanner::init(source);
+    let mut tokens = vec![];
+    loop {
+        let token = scanner.scan_token();
+        if token.kind == TokenType::Eof {
+            return tokens;
+        }
+        tokens.push((token.lexeme, token.line, token.column));
+    }
+}
+
+// A string spanning lines is on the line it ends on, as in clox.
+#[test]
+fn across_lines() {
+    assert_eq!(
+        positions("var é = 1;\n  print é // é\n;\"a\nb\" x"),
+        vec![
+            ("var", 1, 1),
+            ("é", 1, 5),
+            ("=", 1, 7),
+            ("1", 1, 9),
+            (";", 1, 10),
+            ("print", 2, 3),
+            ("é", 2, 9),
+            (";", 3, 1),
+            ("\"a\nb\"", 4, 2),
+            ("x", 4, 4),
+        ]
+    );
+}
+
+// Minified scripts are one long line.
+#[test]
+fn long_line() {
+    let source = "x+".repeat(100_000);
+    let tokens = positions(&source);
+    assert_eq!(tokens.len(), 200_000);
+    assert_eq!(tokens[199_999], ("+", 1, 200_000));
+}
@@ -0,0 +1,19 @@
+use rlox::config::Config;
+
+// Every compile flag has a key in rlox.toml.
+#[test]
+fn compile_flags() {
+    let dir = std::env::temp_dir().join(format!("rlox-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("rlox.toml"),
+        "warn-shadowing = true\nprint-exprs = true\nshared-constants = true\n",
+    )
+    .unwrap();
+    let config = Config::find(&dir.join("script.lox"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    let config = config.unwrap();
+    assert_eq!(config.warn_shadowing, Some(true));
+    assert_eq!(config.print_exprs, Some(true));
+    assert_eq!(config.shared_constants, Some(true));
+}
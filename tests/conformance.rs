@@ -0,0 +1,220 @@
+use rlox::diagnostics::Diagnostic;
+use rlox::lox::Lox;
+use rlox::vm::VmConfig;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Runs Lox scripts annotated the way the craftinginterpreters test suite
+// annotates them, and compares what rlox does with the annotations:
+//
+//     print 1 + 2;  // expect: 3
+//     -"s";         // expect runtime error: Operand must be a number.
+//     (a) = 1;      // Error at '=': Invalid assignment target.
+//     // [line 3] Error at 'c': Expect ')' after parameters.
+//
+// Every script under tests/lox is checked. To score rlox against the
+// official corpus as well, point RLOX_CONFORMANCE_DIR at the test/
+// directory of a craftinginterpreters checkout and run
+// `cargo test --test conformance -- --nocapture`.
+
+// Scripts of tests/lox that rlox is known to get wrong, and why. A script
+// listed here that starts passing fails the run, so the list stays honest.
+const EXPECTED_FAILURES: &[(&str, &str)] = &[
+    (
+        "assignment/grouping.lox",
+        "the error is misspelled 'assigment' and reported at the value",
+    ),
+    (
+        "function/missing_comma_in_parameters.lox",
+        "the error says 'after arguments' instead of 'after parameters'",
+    ),
+    (
+        "variable/use_local_in_initializer.lox",
+        "a local's initializer may read the variable it shadows",
+    ),
+];
+
+// Chapters of the official corpus that only apply to jlox, or are not
+// meant to be run as tests.
+const SKIPPED_CHAPTERS: &[&str] = &["benchmark", "expressions", "scanning"];
+
+#[derive(Debug, Default, PartialEq)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<(usize, String)>,
+    runtime_error: Option<(usize, String)>,
+}
+
+fn parse_expectations(source: &str) -> Expectations {
+    let mut expected = Expectations::default();
+    for (i, line) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let comment = match line.find("// ") {
+            Some(start) => &line[start + 3..],
+            None => continue,
+        };
+        if let Some(output) = comment.strip_prefix("expect: ") {
+            expected.output.push(output.to_string());
+        } else if let Some(msg) = comment.strip_prefix("expect runtime error: ") {
+            expected.runtime_error = Some((line_number, msg.to_string()));
+        } else if let Some((line, error)) = error_annotation(comment, line_number) {
+            expected.compile_errors.push((line, error));
+        }
+    }
+    expected
+}
+
+// Parses `Error at 'x': msg`, optionally preceded by `[line N]` or
+// `[c line N]`. Annotations for jlox only (`[java line N]`) are ignored.
+fn error_annotation(comment: &str, line_number: usize) -> Option<(usize, String)> {
+    let (line, rest) = if let Some(rest) = comment.strip_prefix('[') {
+        let rest = rest.strip_prefix("c ").unwrap_or(rest);
+        let rest = rest.strip_prefix("line ")?;
+        let end = rest.find(']')?;
+        (rest[..end].parse().ok()?, &rest[end + 1..])
+    } else {
+        (line_number, comment)
+    };
+    let rest = rest.trim_start().strip_prefix("Error")?;
+    let msg = if let Some(msg) = rest.strip_prefix(": ") {
+        msg
+    } else if let Some(msg) = rest.strip_prefix(" at end: ") {
+        msg
+    } else {
+        let lexeme = rest.strip_prefix(" at '")?;
+        &lexeme[lexeme.find("': ")? + 3..]
+    };
+    Some((line, msg.to_string()))
+}
+
+fn is_compile_error(diagnostic: &Diagnostic) -> bool {
+    let code = diagnostic.code.code();
+    code.starts_with("E0") || code.starts_with("E1")
+}
+
+fn run(path: &Path) -> Result<(), String> {
+    let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let expected = parse_expectations(&source);
+    let lox = Lox {
+        prelude: false,
+        config: VmConfig {
+            max_instructions: Some(100_000_000),
+            ..VmConfig::default()
+        },
+        ..Lox::default()
+    };
+    let outcome = lox.run_source_capture(&source);
+
+    let compile_errors: Vec<(usize, String)> = outcome
+        .diagnostics
+        .iter()
+        .filter(|d| is_compile_error(d))
+        .map(|d| (d.line, d.message.clone()))
+        .collect();
+    if compile_errors != expected.compile_errors {
+        return Err(format!(
+            "expected compile errors {:?}, got {:?}",
+            expected.compile_errors, compile_errors
+        ));
+    }
+    let runtime_error = outcome
+        .diagnostics
+        .iter()
+        .find(|d| d.code.code().starts_with("E2"))
+        .map(|d| (d.line, d.message.clone()));
+    if runtime_error != expected.runtime_error {
+        return Err(format!(
+            "expected runtime error {:?}, got {:?}",
+            expected.runtime_error, runtime_error
+        ));
+    }
+    let output: Vec<&str> = outcome.stdout.lines().collect();
+    if output != expected.output {
+        return Err(format!(
+            "expected output {:?}, got {:?}",
+            expected.output, output
+        ));
+    }
+    Ok(())
+}
+
+fn scripts(dir: &Path) -> Vec<PathBuf> {
+    let mut scripts = vec![];
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            scripts.extend(self::scripts(&path));
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            scripts.push(path);
+        }
+    }
+    scripts
+}
+
+// The path of `script` relative to `root`, with '/' separators.
+fn name(root: &Path, script: &Path) -> String {
+    let relative = script.strip_prefix(root).unwrap();
+    let parts: Vec<_> = relative.iter().map(|part| part.to_string_lossy()).collect();
+    parts.join("/")
+}
+
+#[test]
+fn local_corpus() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let scripts = scripts(&root);
+    let mut problems = vec![];
+    let mut passed = 0;
+    for script in &scripts {
+        let name = name(&root, script);
+        let known = EXPECTED_FAILURES.iter().find(|(known, _)| *known == name);
+        match (run(script), known) {
+            (Ok(()), None) => passed += 1,
+            (Ok(()), Some(_)) => problems.push(format!(
+                "{}: passes now, remove it from EXPECTED_FAILURES",
+                name
+            )),
+            (Err(msg), None) => problems.push(format!("{}: {}", name, msg)),
+            (Err(_), Some(_)) => {}
+        }
+    }
+    println!("conformance: {}/{} scripts pass", passed, scripts.len());
+    assert!(problems.is_empty(), "{}", problems.join("\n"));
+}
+
+#[test]
+fn official_corpus() {
+    let root = match std::env::var_os("RLOX_CONFORMANCE_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => return,
+    };
+    let mut chapters: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    for script in scripts(&root) {
+        let name = name(&root, &script);
+        let chapter = match name.split_once('/') {
+            Some((chapter, _)) => chapter.to_string(),
+            None => continue,
+        };
+        if SKIPPED_CHAPTERS.contains(&chapter.as_str()) {
+            continue;
+        }
+        let score = chapters.entry(chapter).or_default();
+        score.1 += 1;
+        match run(&script) {
+            Ok(()) => score.0 += 1,
+            Err(msg) => println!("FAIL {}: {}", name, msg),
+        }
+    }
+    let (passed, total) = chapters
+        .values()
+        .fold((0, 0), |(p, t), (passed, total)| (p + passed, t + total));
+    println!();
+    for (chapter, (passed, total)) in &chapters {
+        println!("{:<20} {:>4}/{:<4}", chapter, passed, total);
+    }
+    println!("{:<20} {:>4}/{:<4}", "total", passed, total);
+}
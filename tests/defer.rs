@@ -0,0 +1,57 @@
+use rlox::chunk::Function;
+use rlox::chunk::Value;
+use rlox::compiler::Parser;
+use rlox::vm::InterpretResult;
+use rlox::vm::VmConfig;
+use rlox::vm::VM;
+
+// A deferred statement is compiled once where it is written, to check it,
+// and again wherever it runs. Only the second compile stays in the chunk.
+
+const SCRIPT: &str = r#"
+    {
+      defer {
+        fun bye() { print "bye"; }
+        bye();
+      }
+      print "hi";
+    }
+"#;
+
+fn compile(shared_constants: bool) -> Function {
+    let mut parser = Parser::init(SCRIPT);
+    parser.shared_constants = shared_constants;
+    parser.compile().unwrap()
+}
+
+fn count(function: &Function, constant: &str) -> usize {
+    function
+        .chunk
+        .constants
+        .iter()
+        .filter(|c| **c == Value::string(constant))
+        .count()
+}
+
+#[test]
+fn no_orphans() {
+    for shared_constants in [false, true] {
+        let script = compile(shared_constants);
+        assert_eq!(script.chunk.functions.len(), 1);
+        assert_eq!(count(&script, "hi"), 1);
+        let mut vm = VM::new(script, VmConfig::default());
+        vm.output = Some(String::new());
+        assert_eq!(vm.run(), InterpretResult::Ok);
+        assert_eq!(vm.output.take().unwrap(), "hi\nbye\n");
+    }
+}
+
+// Each exit from the scope runs its own copy.
+#[test]
+fn a_copy_per_exit() {
+    let mut parser =
+        Parser::init("fun f(x) {\n  defer print \"bye\";\n  if (x) return 1;\n  return 2;\n}\n");
+    let script = parser.compile().unwrap();
+    let f = &script.chunk.functions[0];
+    assert_eq!(count(f, "bye"), 3);
+}
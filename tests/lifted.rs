@@ -0,0 +1,34 @@
+use rlox::chunk::Value;
+use rlox::natives;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// A local captured by a closure moves to a cell shared with it. The value
+// in the cell is still a number, a string, and so on, to anything asking.
+
+fn lifted(v: Value) -> Value {
+    Value::Lifted(Rc::new(RefCell::new(v)))
+}
+
+#[test]
+fn predicates_look_inside() {
+    for v in [Value::Number(1.5), Value::from_int(2)] {
+        assert!(v.is_number());
+        assert!(lifted(v).is_number());
+    }
+    assert!(lifted(Value::from_int(2)).is_integer());
+    assert!(!lifted(Value::Number(1.5)).is_integer());
+    assert!(!lifted(Value::string("1.5")).is_number());
+}
+
+#[test]
+fn formatted_as_numbers() {
+    let format = natives::format();
+    let args = [
+        Value::string("[{:5}|{:5}]"),
+        lifted(Value::Number(1.5)),
+        lifted(Value::from_int(2)),
+    ];
+    let formatted = (format.function)(&args).unwrap();
+    assert_eq!(formatted, Value::string("[  1.5|    2]"));
+}
@@ -0,0 +1,70 @@
+use rlox::compiler::CompileLimits;
+use rlox::lox::ExitKind;
+use rlox::lox::Lox;
+
+// Compiles generated programs against small chunk limits, to check that
+// outgrowing them is a compile error at the line that did, not a panic.
+
+fn lox(limits: CompileLimits) -> Lox {
+    Lox {
+        prelude: false,
+        limits,
+        ..Lox::default()
+    }
+}
+
+// `count` lines of `print <n>;`, each printing a different number.
+fn prints(count: usize) -> String {
+    (0..count).map(|n| format!("print {};\n", n)).collect()
+}
+
+#[test]
+fn too_many_constants() {
+    let lox = lox(CompileLimits {
+        max_constants: 100,
+        ..CompileLimits::default()
+    });
+    let outcome = lox.run_source_capture(&prints(150));
+    assert_eq!(outcome.result, ExitKind::CompileError);
+    assert_eq!(outcome.diagnostics.len(), 1);
+    let error = &outcome.diagnostics[0];
+    assert_eq!(error.code.code(), "E1012");
+    assert_eq!(error.message, "Too many constants in one chunk.");
+    assert_eq!(error.line, 101);
+}
+
+#[test]
+fn too_much_code() {
+    let lox = lox(CompileLimits {
+        max_code: 1000,
+        ..CompileLimits::default()
+    });
+    // each line compiles to a 5-byte OP_CONSTANT and an OP_PRINT
+    let outcome = lox.run_source_capture(&prints(200));
+    assert_eq!(outcome.result, ExitKind::CompileError);
+    assert_eq!(outcome.diagnostics.len(), 1);
+    let error = &outcome.diagnostics[0];
+    assert_eq!(error.message, "Too much code in one chunk.");
+    assert_eq!(error.line, 167);
+}
+
+// The limits apply to each function on its own.
+#[test]
+fn limits_are_per_function() {
+    let lox = lox(CompileLimits {
+        max_constants: 100,
+        ..CompileLimits::default()
+    });
+    let source: String = (0..3)
+        .map(|f| format!("fun f{}() {{\n{}}}\n", f, prints(60)))
+        .collect();
+    let outcome = lox.run_source_capture(&source);
+    assert_eq!(outcome.result, ExitKind::Ok);
+}
+
+#[test]
+fn large_programs_compile_with_the_default_limits() {
+    let outcome = lox(CompileLimits::default()).run_source_capture(&prints(100_000));
+    assert_eq!(outcome.result, ExitKind::Ok);
+    assert_eq!(outcome.stdout.lines().count(), 100_000);
+}
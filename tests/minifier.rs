@@ -0,0 +1,37 @@
+use rlox::compiler::Parser;
+use rlox::lox::ExitKind;
+use rlox::lox::Lox;
+use rlox::minifier;
+use rlox::natives;
+
+// Minifies scripts and checks that they still print what they did.
+
+fn minify(source: &str) -> String {
+    let mut parser = Parser::init(source);
+    parser.natives = natives::standard();
+    minifier::minify(parser, source).unwrap()
+}
+
+fn run(source: &str) -> String {
+    let lox = Lox {
+        prelude: false,
+        ..Lox::default()
+    };
+    let outcome = lox.run_source_capture(source);
+    assert_eq!(outcome.result, ExitKind::Ok, "{}", source);
+    outcome.stdout
+}
+
+// Enough bindings for the generated names to reach 'div', which must not
+// hide the native.
+#[test]
+fn short_names_skip_natives() {
+    let mut source: String = (0..3000)
+        .map(|i| format!("{{ var v{} = 0; v{}; }}\n", i, i))
+        .collect();
+    source.push_str("{ var seven = 7; print div(seven, 2); }\n");
+    let minified = minify(&source);
+    assert!(!minified.contains("var div"));
+    assert_eq!(run(&minified), run(&source));
+    assert_eq!(run(&source), "3\n");
+}
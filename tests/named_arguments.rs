@@ -0,0 +1,22 @@
+use rlox::lox::ExitKind;
+use rlox::lox::Lox;
+
+// Compiles calls with badly placed or repeated named arguments, and checks
+// each mistake has its own error code, and so its own explanation.
+
+fn error_code(source: &str) -> &'static str {
+    let lox = Lox {
+        prelude: false,
+        ..Lox::default()
+    };
+    let outcome = lox.run_source_capture(source);
+    assert_eq!(outcome.result, ExitKind::CompileError);
+    assert_eq!(outcome.diagnostics.len(), 1);
+    outcome.diagnostics[0].code.code()
+}
+
+#[test]
+fn codes() {
+    assert_eq!(error_code("fun f(a, b) {}\nf(a: 1, a: 2);"), "E1009");
+    assert_eq!(error_code("fun f(a, b) {}\nf(a: 1, 2);"), "E1013");
+}
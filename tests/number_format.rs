@@ -0,0 +1,75 @@
+use rlox::chunk::format_number;
+use rlox::lox::ExitKind;
+use rlox::lox::Lox;
+
+// Checks that every printed number reads back as the number printed, both
+// when parsed as a float and when compiled as a Lox literal.
+
+#[test]
+fn printed_numbers_round_trip() {
+    // a fixed sequence of bit patterns, covering every exponent
+    let mut bits: u64 = 0x9e37_79b9_7f4a_7c15;
+    for _ in 0..100_000 {
+        bits = bits
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        let n = f64::from_bits(bits);
+        if n.is_nan() {
+            continue;
+        }
+        let text = format_number(n);
+        assert_eq!(text.parse::<f64>(), Ok(n), "{} printed as {}", n, text);
+    }
+}
+
+#[test]
+fn extremes() {
+    assert_eq!(format_number(f64::MAX), "1.7976931348623157e+308");
+    assert_eq!(format_number(f64::MIN_POSITIVE), "2.2250738585072014e-308");
+    assert_eq!(format_number(5e-324), "5e-324");
+    assert_eq!(format_number(f64::INFINITY), "inf");
+    assert_eq!(format_number(f64::NAN), "NaN");
+}
+
+// What `print <text>;` prints, compiled as a Lox script.
+fn reprint(text: &str) -> String {
+    let lox = Lox {
+        prelude: false,
+        ..Lox::default()
+    };
+    let outcome = lox.run_source_capture(&format!("print {};", text));
+    assert_eq!(outcome.result, ExitKind::Ok, "{} does not compile", text);
+    outcome.stdout.trim_end().to_string()
+}
+
+#[test]
+fn printed_numbers_are_literals() {
+    let edges = [
+        0.1,
+        1e-7,
+        -3e-8,
+        1.5e-7,
+        1e21,
+        1.2345e24,
+        f64::MAX,
+        f64::MIN_POSITIVE,
+        5e-324,
+        1e20,
+    ];
+    for n in edges {
+        let text = format_number(n);
+        assert_eq!(reprint(&text), text);
+    }
+    let mut bits: u64 = 0x2545_f491_4f6c_dd1d;
+    for _ in 0..2_000 {
+        bits = bits
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        let n = f64::from_bits(bits);
+        if !n.is_finite() {
+            continue;
+        }
+        let text = format_number(n);
+        assert_eq!(reprint(&text), text);
+    }
+}
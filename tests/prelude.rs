@@ -0,0 +1,17 @@
+use rlox::lox::ExitKind;
+use rlox::lox::Lox;
+
+// The prelude compiles whatever natives the embedder gives.
+#[test]
+fn prelude_without_the_standard_natives() {
+    let lox = Lox {
+        natives: vec![],
+        ..Lox::default()
+    };
+    let outcome = lox.run_source_capture(
+        "fun less(a, b) { return a < b; }\nprint sort((3, 1, 2), less);\nprint max(1, 2);",
+    );
+    assert_eq!(outcome.diagnostics, vec![]);
+    assert_eq!(outcome.result, ExitKind::Ok);
+    assert_eq!(outcome.stdout, "(1, 2, 3)\n2\n");
+}
@@ -0,0 +1,41 @@
+use rlox::compiler::Parser;
+use rlox::natives;
+use rlox::vm::VmConfig;
+use rlox::vm::VM;
+
+// Runs scripts compiled with `print_exprs`, the `--print-exprs` flag.
+
+fn traced(source: &str) -> String {
+    let mut parser = Parser::init(source);
+    parser.natives = natives::standard();
+    parser.print_exprs = true;
+    parser.load_prelude();
+    let mut vm = VM::new(parser.compile().unwrap(), VmConfig::default());
+    vm.output = Some(String::new());
+    vm.run();
+    vm.output.take().unwrap()
+}
+
+#[test]
+fn expression_statements_print_their_value() {
+    let source = "var x = 1;\n\
+                  x + 1;\n\
+                  x = 3;\n\
+                  print x;\n\
+                  fun f() {\n  \"in f\";\n}\n\
+                  f();\n\
+                  (1,\n 2);\n";
+    let expected = "[line 2] 2\n\
+                    [line 3] 3\n\
+                    3\n\
+                    [line 6] in f\n\
+                    [line 8] nil\n\
+                    [line 9] (1, 2)\n";
+    assert_eq!(traced(source), expected);
+}
+
+// The prelude's own expression statements are not traced.
+#[test]
+fn prelude_is_not_traced() {
+    assert_eq!(traced("print map((1, 2), str);\n"), "(1, 2)\n");
+}
@@ -0,0 +1,110 @@
+use rlox::chunk::Function;
+use rlox::chunk::Value;
+use rlox::compiler::CompileLimits;
+use rlox::compiler::Parser;
+use rlox::diagnostics::CollectingReporter;
+use rlox::natives;
+use rlox::vm::InterpretResult;
+use rlox::vm::VmConfig;
+use rlox::vm::VM;
+use std::rc::Rc;
+
+// Compiles scripts with one constant pool for all their functions, and
+// checks that they share it and run as they do with a pool each.
+
+const SCRIPT: &str = r#"
+    fun greet(name) { print "hello " + name; return 1.5; }
+    fun twice(name) {
+      fun again() { return greet(name); }
+      greet(name);
+      return again() + 1.5;
+    }
+    print twice("you");
+    print "hello " + "me";
+    print len("hello ");
+"#;
+
+fn compile(source: &str, shared_constants: bool) -> Function {
+    let mut parser = Parser::init(source);
+    parser.natives = natives::standard();
+    parser.shared_constants = shared_constants;
+    parser.compile().unwrap()
+}
+
+fn run(script: Function) -> (InterpretResult, String) {
+    let mut vm = VM::new(script, VmConfig::default());
+    vm.output = Some(String::new());
+    let result = vm.run();
+    (result, vm.output.take().unwrap())
+}
+
+// The script and every function declared in it.
+fn functions(function: &Function) -> Vec<&Function> {
+    let mut all = vec![function];
+    for nested in &function.chunk.functions {
+        all.extend(functions(nested));
+    }
+    all
+}
+
+#[test]
+fn runs_the_same() {
+    let expected = "hello you\nhello you\n3\nhello me\n6\n".to_string();
+    assert_eq!(
+        run(compile(SCRIPT, false)),
+        (InterpretResult::Ok, expected.clone())
+    );
+    assert_eq!(run(compile(SCRIPT, true)), (InterpretResult::Ok, expected));
+}
+
+#[test]
+fn functions_share_one_pool() {
+    let script = compile(SCRIPT, true);
+    let all = functions(&script);
+    assert_eq!(all.len(), 4);
+    for function in &all {
+        assert!(Rc::ptr_eq(
+            &function.chunk.constants,
+            &script.chunk.constants
+        ));
+    }
+    let hellos = script
+        .chunk
+        .constants
+        .iter()
+        .filter(|constant| **constant == Value::string("hello "))
+        .count();
+    assert_eq!(hellos, 1);
+
+    let separate: usize = functions(&compile(SCRIPT, false))
+        .iter()
+        .map(|function| function.chunk.constants.len())
+        .sum();
+    assert!(script.chunk.constants.len() < separate);
+}
+
+// With one pool, the constant limit applies to the whole script.
+#[test]
+fn limit_counts_the_pool() {
+    let source: String = (0..3)
+        .map(|f| {
+            let prints: String = (0..40)
+                .map(|n| format!("print {};\n", f * 40 + n))
+                .collect();
+            format!("fun f{}() {{\n{}}}\n", f, prints)
+        })
+        .collect();
+    let mut parser = Parser::init(&source);
+    parser.shared_constants = true;
+    parser.limits = CompileLimits {
+        max_constants: 100,
+        ..CompileLimits::default()
+    };
+    let collected = Rc::new(CollectingReporter::default());
+    parser.reporter = collected.clone();
+    assert!(parser.compile().is_none());
+    let diagnostics = collected.take();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Too many constants in one script.");
+    assert_eq!(diagnostics[0].line, 106);
+}
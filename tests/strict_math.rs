@@ -0,0 +1,72 @@
+use rlox::lox::ExitKind;
+use rlox::lox::Lox;
+use rlox::vm::VmConfig;
+
+// Runs arithmetic with VmConfig::strict_math, the `--strict-math` flag.
+
+fn run(source: &str) -> Result<String, String> {
+    let lox = Lox {
+        prelude: false,
+        config: VmConfig {
+            strict_math: true,
+            ..VmConfig::default()
+        },
+        ..Lox::default()
+    };
+    let outcome = lox.run_source_capture(source);
+    match outcome.result {
+        ExitKind::Ok => Ok(outcome.stdout),
+        _ => {
+            let error = &outcome.diagnostics[0];
+            assert_eq!(error.code.code(), "E2019");
+            Err(error.message.clone())
+        }
+    }
+}
+
+// 10 to the power 400, an integer far beyond an f64
+const BIG: &str = "var big = 1; for (var i = 0; i < 400; i = i + 1) big = big * 10;";
+
+#[test]
+fn finite_results_are_unchanged() {
+    assert_eq!(
+        run("print 1 / 4; print 0.5 * 3; print 2 - 0.25; print 0 / 5;"),
+        Ok("0.25\n1.5\n1.75\n0\n".to_string())
+    );
+    // integers never overflow, past an i64 they become big integers
+    assert_eq!(
+        run(&format!("{} print big > 0;", BIG)),
+        Ok("true\n".to_string())
+    );
+}
+
+#[test]
+fn division_by_zero() {
+    assert_eq!(run("print 1 / 0;"), Err("Division by zero.".to_string()));
+    assert_eq!(run("print 0 / 0;"), Err("Division by zero.".to_string()));
+    assert_eq!(
+        run("print 1.5 / -0.0;"),
+        Err("Division by zero.".to_string())
+    );
+}
+
+#[test]
+fn overflow_to_infinity() {
+    let doubling = "var x = 1.5; while (true) x = x * x;";
+    assert_eq!(
+        run(doubling),
+        Err("Arithmetic overflowed to infinity.".to_string())
+    );
+    assert_eq!(
+        run(&format!("{} print big + 0.5;", BIG)),
+        Err("Arithmetic overflowed to infinity.".to_string())
+    );
+}
+
+#[test]
+fn nan() {
+    assert_eq!(
+        run(&format!("{} print big * 0.0;", BIG)),
+        Err("Arithmetic produced NaN.".to_string())
+    );
+}
@@ -0,0 +1,90 @@
+use rlox::chunk::Native;
+use rlox::chunk::Structured;
+use rlox::chunk::Value;
+use rlox::lox::ExitKind;
+use rlox::lox::Lox;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+// Hands values out of a script through a native, the way an embedder
+// would, and inspects them as structured data.
+
+fn kept(source: &str) -> Vec<Structured> {
+    let kept = Rc::new(RefCell::new(vec![]));
+    let mut keep = Native::new("keep", 1, |_| Ok(Value::Nil));
+    let values = kept.clone();
+    keep.function = Rc::new(move |args: &[Value]| {
+        values.borrow_mut().push(args[0].to_structured());
+        Ok(Value::Nil)
+    });
+    let mut lox = Lox {
+        prelude: false,
+        ..Lox::default()
+    };
+    lox.natives.push(keep);
+    assert_eq!(lox.run_source_capture(source).result, ExitKind::Ok);
+    let values = kept.borrow().clone();
+    values
+}
+
+#[test]
+fn scalars() {
+    assert_eq!(
+        kept(
+            "keep(nil); keep(true); keep(1); keep(1.5); keep(\"s\"); keep(100000000000000000000);"
+        ),
+        vec![
+            Structured::Nil,
+            Structured::Bool(true),
+            Structured::Int(1),
+            Structured::Number(1.5),
+            Structured::String("s".to_string()),
+            Structured::BigInt("100000000000000000000".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn nested_values() {
+    let values = kept(
+        "var b = stringBuilder(); append(b, \"built\");
+         fun f() {}
+         keep({name: \"a\", tags: (1, b), run: f, now: clock});",
+    );
+    let mut record = BTreeMap::new();
+    record.insert("name".to_string(), Structured::String("a".to_string()));
+    record.insert(
+        "tags".to_string(),
+        Structured::Tuple(vec![
+            Structured::Int(1),
+            Structured::String("built".to_string()),
+        ]),
+    );
+    record.insert(
+        "run".to_string(),
+        Structured::Function("<fn f>".to_string()),
+    );
+    record.insert(
+        "now".to_string(),
+        Structured::Function("<native fn>".to_string()),
+    );
+    assert_eq!(values, vec![Structured::Record(record)]);
+}
+
+// Captured variables are copied like any other.
+#[test]
+fn captured_values() {
+    let values = kept("var x = (1, 2); fun f() { return x; } keep(x); keep(f());");
+    let tuple = Structured::Tuple(vec![Structured::Int(1), Structured::Int(2)]);
+    assert_eq!(values, vec![tuple.clone(), tuple]);
+}
+
+#[test]
+fn serializes_as_plain_data() {
+    let values = kept("keep({name: \"a\", tags: (1, 2.5), bytes: bytes(2)});");
+    assert_eq!(
+        toml::to_string(&values[0]).unwrap(),
+        "bytes = [0, 0]\nname = \"a\"\ntags = [1, 2.5]\n"
+    );
+}
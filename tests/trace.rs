@@ -0,0 +1,153 @@
+use rlox::chunk::Native;
+use rlox::chunk::Permissions;
+use rlox::chunk::Symbol;
+use rlox::chunk::Value;
+use rlox::compiler::Parser;
+use rlox::natives;
+use rlox::trace::Trace;
+use rlox::vm::InterpretResult;
+use rlox::vm::VmConfig;
+use rlox::vm::VM;
+use std::rc::Rc;
+
+// Records scripts to a trace file and replays them, with natives that fail
+// when called, to check that replaying reuses every recorded result.
+
+fn run(source: &str, natives: Vec<Native>, trace: Trace) -> (InterpretResult, String) {
+    let mut parser = Parser::init(source);
+    parser.natives = natives;
+    parser.load_prelude();
+    let script = parser.compile().unwrap();
+    let config = VmConfig {
+        permissions: Permissions::all(),
+        ..VmConfig::default()
+    };
+    let mut vm = VM::new(script, config);
+    vm.output = Some(String::new());
+    vm.trace = Some(trace);
+    let result = vm.run();
+    vm.trace.as_mut().unwrap().finish().unwrap();
+    (result, vm.output.take().unwrap())
+}
+
+// Runs `source` once recording and once replaying, and returns what both
+// runs printed.
+fn round_trip(name: &str, source: &str, natives: Vec<Native>) -> String {
+    let path = std::env::temp_dir().join(format!("rlox-trace-{}-{}", name, std::process::id()));
+    let path = path.to_str().unwrap();
+    let recorded = run(source, natives.clone(), Trace::record(path).unwrap());
+    let unavailable = natives
+        .into_iter()
+        .map(|mut native| {
+            native.function = Rc::new(|_| Err("called while replaying".to_string()));
+            native
+        })
+        .collect();
+    let replayed = run(source, unavailable, Trace::replay(path).unwrap());
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(recorded.0, InterpretResult::Ok);
+    assert_eq!(replayed, recorded);
+    recorded.1
+}
+
+#[test]
+fn tuples() {
+    let output = round_trip(
+        "tuples",
+        "print range(0, 3, 1);\nprint reverse((1, \"a\", nil));\nfun less(a, b) { return a < b; }\nprint sort((3, 1, 2), less);",
+        natives::standard(),
+    );
+    assert_eq!(output, "(0, 1, 2)\n(nil, a, 1)\n(1, 2, 3)\n");
+}
+
+#[test]
+fn big_integers_and_records() {
+    let mut natives = natives::standard();
+    natives.push(Native::new("big", 0, |_| {
+        Ok(Value::big_int(
+            "123456789012345678901234567890".parse().unwrap(),
+        ))
+    }));
+    natives.push(Native::new("point", 0, |_| {
+        Ok(Value::record(vec![
+            (Symbol::intern("y"), Value::from_int(2)),
+            (Symbol::intern("x"), Value::from_int(1)),
+        ]))
+    }));
+    let output = round_trip(
+        "big",
+        "print big();\nvar p = point();\nprint p.x + p.y;",
+        natives,
+    );
+    assert_eq!(output, "123456789012345678901234567890\n3\n");
+}
+
+#[test]
+fn bytes() {
+    let output = round_trip(
+        "bytes",
+        "var b = encodeUtf8(\"héllo\");\nprint bytes(2)[1];\nprint decodeUtf8(slice(b, 0, 3));\nprint reverse(b)[0];",
+        natives::standard(),
+    );
+    assert_eq!(output, "0\nhé\n111\n");
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn regex_matches() {
+    let output = round_trip(
+        "regex",
+        r#"print reMatch("(a)(x)?(b)", "cab");
+print reMatch("z", "cab");
+print reFindAll("[0-9]+", "1 22 333");"#,
+        natives::standard(),
+    );
+    assert_eq!(output, "(ab, a, nil, b)\nnil\n(1, 22, 333)\n");
+}
+
+// httpGet() against a server answering one request, which is gone by the
+// time the trace is replayed.
+#[cfg(feature = "http")]
+#[test]
+fn http_responses() {
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = vec![];
+        let mut buffer = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buffer).unwrap();
+            request.extend_from_slice(&buffer[..n]);
+        }
+        stream
+            .write_all(
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\nConnection: close\r\n\r\ngone",
+            )
+            .unwrap();
+    });
+    let mut natives = natives::standard();
+    natives.extend(natives::network());
+    let source = format!(
+        "var response = httpGet(\"http://127.0.0.1:{}/\");\nprint response;",
+        port
+    );
+    let output = round_trip("http", &source, natives);
+    server.join().unwrap();
+    assert_eq!(output, "(404, gone)\n");
+}
+
+// append() returns the builder it was given, which replaying fills in place.
+#[test]
+fn string_builders() {
+    let output = round_trip(
+        "builders",
+        "var b = stringBuilder();\nappend(append(b, \"a\"), 1);\nprint b;\nvar c = b;\nappend(c, nil);\nprint toString(b);\nprint stringBuilder();",
+        natives::standard(),
+    );
+    assert_eq!(output, "<string builder 2>\na1nil\n<string builder 0>\n");
+}